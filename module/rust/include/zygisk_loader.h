@@ -0,0 +1,66 @@
+/*
+ * Zygisk-Loader payload entry point.
+ *
+ * A payload may export `zygisk_loader_main`. The loader calls it once, right after the payload
+ * is loaded (and after its constructors ran), with what it knows about the process. A non-zero
+ * return marks the payload as failed, so payloads that `require` it are skipped.
+ *
+ * Every pointer in the context is only valid during the call; copy what you need to keep.
+ * Fields are only ever appended: check `version` (or `size`) before reading newer ones.
+ *
+ * Mirrors `zygisk_loader.rs`.
+ */
+#ifndef ZYGISK_LOADER_H
+#define ZYGISK_LOADER_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Version of `struct zygisk_loader_context` filled in by this loader. */
+#define ZYGISK_LOADER_CONTEXT_VERSION 1
+
+/* Name of the entry point the loader looks up. */
+#define ZYGISK_LOADER_ENTRY "zygisk_loader_main"
+
+/* An `arg.<key> = <value>` pair of the matched target. */
+struct zygisk_loader_arg {
+    const char *key;
+    const char *value;
+};
+
+struct zygisk_loader_context {
+    /* ZYGISK_LOADER_CONTEXT_VERSION of the loader that filled it in. */
+    uint32_t version;
+    /* sizeof(struct zygisk_loader_context) as known to the loader. */
+    uint32_t size;
+    /* Loader version, e.g. "0.3.3". */
+    const char *loader_version;
+    /* Process name, e.g. "com.example.app:remote". */
+    const char *process_name;
+    /* Package of the matched target section. */
+    const char *package_name;
+    /* App data directory; empty when unknown. */
+    const char *app_data_dir;
+    /* ABI as named by ART: "arm", "arm64", "x86", "x86_64" or "riscv64". */
+    const char *instruction_set;
+    uint32_t uid;
+    /* The process JavaVM (a `JavaVM *`), or NULL. */
+    void *java_vm;
+    /* Per-target arguments, in declaration order. */
+    const struct zygisk_loader_arg *args;
+    size_t arg_count;
+};
+
+typedef int (*zygisk_loader_entry_t)(const struct zygisk_loader_context *context);
+
+int zygisk_loader_main(const struct zygisk_loader_context *context);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* ZYGISK_LOADER_H */
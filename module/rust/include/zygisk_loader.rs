@@ -0,0 +1,51 @@
+// Zygisk-Loader payload entry point, mirroring `zygisk_loader.h`.
+//
+// Copy this file into a payload crate or `include!` it, then export the entry point:
+//
+//     #[no_mangle]
+//     pub unsafe extern "C" fn zygisk_loader_main(context: *const ZygiskLoaderContext) -> i32
+//
+// The loader calls it once, right after the payload is loaded. A non-zero return marks the
+// payload as failed. Every pointer in the context is only valid during the call.
+
+/// Version of [ZygiskLoaderContext] filled in by this loader. Fields are only ever appended.
+pub const ZYGISK_LOADER_CONTEXT_VERSION: u32 = 1;
+
+/// Name of the entry point the loader looks up.
+pub const ZYGISK_LOADER_ENTRY: &core::ffi::CStr = c"zygisk_loader_main";
+
+/// An `arg.<key> = <value>` pair of the matched target.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ZygiskLoaderArg {
+    pub key: *const core::ffi::c_char,
+    pub value: *const core::ffi::c_char,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct ZygiskLoaderContext {
+    /// [ZYGISK_LOADER_CONTEXT_VERSION] of the loader that filled it in.
+    pub version: u32,
+    /// Size of this struct as known to the loader.
+    pub size: u32,
+    /// Loader version, e.g. `0.3.3`.
+    pub loader_version: *const core::ffi::c_char,
+    /// Process name, e.g. `com.example.app:remote`.
+    pub process_name: *const core::ffi::c_char,
+    /// Package of the matched target section.
+    pub package_name: *const core::ffi::c_char,
+    /// App data directory; empty when unknown.
+    pub app_data_dir: *const core::ffi::c_char,
+    /// ABI as named by ART: `arm`, `arm64`, `x86`, `x86_64` or `riscv64`.
+    pub instruction_set: *const core::ffi::c_char,
+    pub uid: u32,
+    /// The process `JavaVM *`, or null.
+    pub java_vm: *mut core::ffi::c_void,
+    /// Per-target arguments, in declaration order.
+    pub args: *const ZygiskLoaderArg,
+    pub arg_count: usize,
+}
+
+pub type ZygiskLoaderEntry =
+    unsafe extern "C" fn(context: *const ZygiskLoaderContext) -> core::ffi::c_int;
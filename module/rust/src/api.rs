@@ -73,12 +73,12 @@ impl<'a> ZygiskApi<'a> {
     }
 
     /// Get information about the current process.
-    /// Returns bitwise-or'd [StateFlags] values.
+    /// Returns bitwise-or'd [StateFlags] values. Bits this crate does not know about are dropped.
     pub fn get_flags(&self) -> StateFlags {
         self.inner
             .get_flags
             .map(|func| func(self.inner.this))
-            .map(|raw| StateFlags::from_bits(raw).expect("unsupported flag returned by Magisk"))
+            .map(StateFlags::from_bits_truncate)
             .unwrap_or(StateFlags::empty())
     }
 
@@ -186,6 +186,8 @@ impl<'a> ZygiskApi<'a> {
         old_func: Option<&mut *mut ()>,
     ) {
         if let Some(func) = self.inner.plt_hook_register_v4 {
+            // dev_t/ino_t are 32-bit on some ABIs, so the conversion is not always a no-op.
+            #[allow(clippy::useless_conversion)]
             func(
                 dev.try_into().unwrap(),
                 ino.try_into().unwrap(),
@@ -202,10 +204,10 @@ impl<'a> ZygiskApi<'a> {
     /// 
     /// This is available in API v4+ as a replacement for the more complex `plt_hook_exclude`.
     /// Use this to keep specific file descriptors open that your module needs.
-    pub fn exempt_fd(&self, fd: i32) {
-        if let Some(func) = self.inner.exempt_fd {
-            func(fd);
-        }
+    ///
+    /// Returns `false` if the fd could not be exempted, including when the API is older than v4.
+    pub fn exempt_fd(&self, fd: i32) -> bool {
+        self.inner.exempt_fd.is_some_and(|func| func(fd))
     }
 }
 
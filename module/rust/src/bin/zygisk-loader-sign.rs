@@ -0,0 +1,133 @@
+//! Host-side key management, payload signing and encryption for Zygisk-Loader.
+//!
+//! ```text
+//! zygisk-loader-sign keygen <secret key file>      # prints the public key for trusted_keys
+//! zygisk-loader-sign pubkey <secret key file>
+//! zygisk-loader-sign sign <secret key file> <payload.so>...   # writes <payload.so>.sig
+//! zygisk-loader-sign verify <trusted_keys> <payload.so>...
+//! zygisk-loader-sign payload-keygen <payload.key>
+//! zygisk-loader-sign encrypt <payload.key> <input> <output>
+//! ```
+
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::process::ExitCode;
+
+// The loader is built as a `dylib`, which binaries cannot link against, so the signature and
+// encryption code is compiled into the tool directly.
+#[allow(dead_code)]
+#[path = "../crypt.rs"]
+mod crypt;
+#[allow(dead_code)]
+#[path = "../signature.rs"]
+mod signature;
+
+use crypt::PayloadKey;
+use signature::{encode_hex, signature_path, SecretKey, TrustStore};
+
+const USAGE: &str = "usage:
+  zygisk-loader-sign keygen <secret key file>
+  zygisk-loader-sign pubkey <secret key file>
+  zygisk-loader-sign sign <secret key file> <payload>...
+  zygisk-loader-sign verify <trusted keys file> <payload>...
+  zygisk-loader-sign payload-keygen <payload key file>
+  zygisk-loader-sign encrypt <payload key file> <input> <output>";
+
+fn read_secret(path: &str) -> Result<SecretKey, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    SecretKey::from_hex(&text).ok_or_else(|| format!("{}: not a hex secret key", path))
+}
+
+fn read_random<const N: usize>() -> Result<[u8; N], String> {
+    let mut bytes = [0u8; N];
+    fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes))
+        .map_err(|e| format!("/dev/urandom: {}", e))?;
+    Ok(bytes)
+}
+
+/// Creates `path` readable only by the owner, refusing to overwrite an existing key.
+fn write_secret(path: &str, text: &str) -> Result<(), String> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut f| f.write_all(text.as_bytes()))
+        .map_err(|e| format!("{}: {}", path, e))
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    match args {
+        [cmd, secret] if cmd == "keygen" => {
+            let key = SecretKey::generate().map_err(|e| e.to_string())?;
+            write_secret(secret, &key.to_hex())?;
+            println!("{}", key.public_hex());
+            Ok(())
+        }
+        [cmd, secret] if cmd == "pubkey" => {
+            println!("{}", read_secret(secret)?.public_hex());
+            Ok(())
+        }
+        [cmd, secret, payloads @ ..] if cmd == "sign" && !payloads.is_empty() => {
+            let key = read_secret(secret)?;
+            for payload in payloads {
+                let data = fs::read(payload).map_err(|e| format!("{}: {}", payload, e))?;
+                let sig_path = signature_path(payload);
+                fs::write(&sig_path, key.sign(&data))
+                    .map_err(|e| format!("{}: {}", sig_path, e))?;
+                println!("signed {} -> {}", payload, sig_path);
+            }
+            Ok(())
+        }
+        [cmd, keys, payloads @ ..] if cmd == "verify" && !payloads.is_empty() => {
+            let store = TrustStore::load(keys)
+                .map_err(|e| format!("{}: {}", keys, e))?
+                .ok_or_else(|| format!("{}: not found", keys))?;
+            let mut failed = false;
+            for payload in payloads {
+                let data = fs::read(payload).map_err(|e| format!("{}: {}", payload, e))?;
+                match store.verify_file(payload, &data) {
+                    Ok(()) => println!("{}: OK", payload),
+                    Err(e) => {
+                        println!("{}: {}", payload, e);
+                        failed = true;
+                    }
+                }
+            }
+            if failed {
+                Err("verification failed".to_string())
+            } else {
+                Ok(())
+            }
+        }
+        [cmd, key_path] if cmd == "payload-keygen" => {
+            write_secret(key_path, &encode_hex(&read_random::<32>()?))?;
+            println!("wrote {}", key_path);
+            Ok(())
+        }
+        [cmd, key_path, input, output] if cmd == "encrypt" => {
+            let text = fs::read_to_string(key_path).map_err(|e| format!("{}: {}", key_path, e))?;
+            let key = PayloadKey::parse(&text)
+                .ok_or_else(|| format!("{}: not a hex payload key", key_path))?;
+            let data = fs::read(input).map_err(|e| format!("{}: {}", input, e))?;
+            let sealed = crypt::encrypt(&data, &key, &read_random::<12>()?);
+            fs::write(output, sealed).map_err(|e| format!("{}: {}", output, e))?;
+            println!("encrypted {} -> {}", input, output);
+            Ok(())
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
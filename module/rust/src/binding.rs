@@ -43,7 +43,7 @@ pub(crate) struct RawApiTable {
     // API v4+ functions  
     pub plt_hook_register_v4: 
         Option<extern "C" fn(c_ulong, c_ulong, *const c_char, *mut (), *mut *mut ())>, // dev_t, ino_t variant
-    pub exempt_fd: Option<extern "C" fn(c_int)>,  // v4+ replacement for plt_hook_exclude
+    pub exempt_fd: Option<extern "C" fn(c_int) -> bool>,  // v4+ replacement for plt_hook_exclude
 }
 
 #[repr(C)]
@@ -0,0 +1,420 @@
+//! Root companion.
+//!
+//! Runs in the superuser daemon and serves files the module cannot open itself from a confined
+//! zygote child. Each connection carries one request: a request byte and its arguments,
+//! answered with a status byte and the response body. Files travel as sealed memfds attached
+//! to the status byte with `SCM_RIGHTS`, so the receiver gets an immutable copy no matter how
+//! the original file is labelled or later modified.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::ManuallyDrop;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+use crate::api::ZygiskApi;
+use crate::config::LoaderConfig;
+use crate::crypt::PayloadKey;
+use crate::inject::{is_sealed, sealed_copy};
+
+/// Asks for the payload decryption key. Answered with [`STATUS_OK`] and 32 key bytes.
+pub(crate) const REQUEST_PAYLOAD_KEY: u8 = 1;
+/// Asks for a file, by a little-endian `u32` path length and the path. Answered with
+/// [`STATUS_OK`] and a sealed memfd holding the contents.
+pub(crate) const REQUEST_FILE: u8 = 2;
+
+pub(crate) const STATUS_OK: u8 = 0;
+pub(crate) const STATUS_UNAVAILABLE: u8 = 1;
+/// The path is not one the companion serves.
+pub(crate) const STATUS_DENIED: u8 = 2;
+
+/// Longest path accepted in a [`REQUEST_FILE`].
+const MAX_PATH_LEN: usize = 4096;
+
+/// What the companion may serve.
+pub(crate) struct Sources<'a> {
+    pub payload_key: &'a str,
+    /// Files under this directory are served, except the payload key.
+    pub module_dir: &'a str,
+    /// The config and its legacy fallback. Absolute payload paths they name are served, with
+    /// their signatures.
+    pub config: &'a str,
+    pub legacy_config: &'a str,
+}
+
+impl Sources<'_> {
+    pub(crate) const MODULE: Sources<'static> = Sources {
+        payload_key: crate::PAYLOAD_KEY_PATH,
+        module_dir: crate::MODULE_DIR,
+        config: crate::CONFIG_PATH,
+        legacy_config: crate::LEGACY_CONFIG_PATH,
+    };
+
+    /// Whether `file`, opened from the requested `path`, may be sent to a zygote child. The
+    /// check uses the path the descriptor actually refers to, so swapping a symlink in after
+    /// the request cannot redirect it.
+    fn allows(&self, path: &str, file: &File) -> bool {
+        let Ok(opened) = opened_path(file) else {
+            return false;
+        };
+        if fs::canonicalize(self.payload_key).is_ok_and(|payload_key| opened == payload_key) {
+            return false;
+        }
+        if fs::canonicalize(self.module_dir).is_ok_and(|module_dir| opened.starts_with(module_dir))
+        {
+            return true;
+        }
+        // Absolute payloads named by the config, and their signatures.
+        let payload = path.strip_suffix(".sig").unwrap_or(path);
+        let config = LoaderConfig::load(self.config)
+            .ok()
+            .or_else(|| LoaderConfig::load_legacy(self.legacy_config).ok());
+        config.is_some_and(|config| {
+            config
+                .targets
+                .iter()
+                .flat_map(|target| &target.payloads)
+                .any(|spec| spec.name == payload)
+        })
+    }
+}
+
+/// Companion entry point. The framework owns `socket` and closes it after we return.
+// Test builds register a stub companion instead, see `lib.rs`.
+#[cfg_attr(test, allow(dead_code))]
+pub(crate) fn handle_request(socket: RawFd) {
+    let mut stream = ManuallyDrop::new(unsafe { UnixStream::from_raw_fd(socket) });
+    if let Err(e) = serve(&mut stream, &Sources::MODULE) {
+        error!("Companion request failed: {}", e);
+    }
+}
+
+/// The path `file` was opened at, with every symlink resolved.
+fn opened_path(file: &File) -> io::Result<PathBuf> {
+    fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd()))
+}
+
+fn serve(stream: &mut UnixStream, sources: &Sources) -> io::Result<()> {
+    let mut request = [0u8; 1];
+    stream.read_exact(&mut request)?;
+    match request[0] {
+        REQUEST_PAYLOAD_KEY => match PayloadKey::load(sources.payload_key) {
+            Ok(key) => {
+                stream.write_all(&[STATUS_OK])?;
+                stream.write_all(key.as_bytes())
+            }
+            Err(e) => {
+                warn!("Companion cannot provide {}: {}", sources.payload_key, e);
+                stream.write_all(&[STATUS_UNAVAILABLE])
+            }
+        },
+        REQUEST_FILE => {
+            let path = read_path(stream)?;
+            // Open first and check what was opened, not what the path pointed at earlier.
+            let file = match File::open(&path) {
+                Ok(file) if sources.allows(&path, &file) => file,
+                Ok(_) => {
+                    warn!("Companion refused to serve {}", path);
+                    return stream.write_all(&[STATUS_DENIED]);
+                }
+                Err(_) => return stream.write_all(&[STATUS_UNAVAILABLE]),
+            };
+            match sealed_copy(&file) {
+                Ok(memfd) => send_with_fd(stream, STATUS_OK, memfd.as_raw_fd()),
+                Err(e) => {
+                    warn!("Companion cannot provide {}: {}", path, e);
+                    stream.write_all(&[STATUS_UNAVAILABLE])
+                }
+            }
+        }
+        other => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown request {}", other),
+        )),
+    }
+}
+
+fn read_path(stream: &mut UnixStream) -> io::Result<String> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_PATH_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("path of {} bytes", len),
+        ));
+    }
+    let mut path = vec![0u8; len];
+    stream.read_exact(&mut path)?;
+    String::from_utf8(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Sends `byte` with `fd` attached as `SCM_RIGHTS`.
+fn send_with_fd(stream: &UnixStream, byte: u8, fd: RawFd) -> io::Result<()> {
+    let mut data = [byte];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr().cast(),
+        iov_len: data.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) } as usize;
+    // u64 words keep the control buffer aligned for `cmsghdr`.
+    let mut control = vec![0u64; space.div_ceil(8)];
+    let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr().cast();
+    message.msg_controllen = space as _;
+    unsafe {
+        let header = libc::CMSG_FIRSTHDR(&message);
+        (*header).cmsg_level = libc::SOL_SOCKET;
+        (*header).cmsg_type = libc::SCM_RIGHTS;
+        (*header).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(header).cast::<RawFd>(), fd);
+        if libc::sendmsg(stream.as_raw_fd(), &message, libc::MSG_NOSIGNAL) != 1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Receives one byte and the fd attached to it, if any.
+fn recv_with_fd(stream: &UnixStream) -> io::Result<(u8, Option<OwnedFd>)> {
+    let mut data = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr().cast(),
+        iov_len: data.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u64; space.div_ceil(8)];
+    let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr().cast();
+    message.msg_controllen = space as _;
+    let received =
+        unsafe { libc::recvmsg(stream.as_raw_fd(), &mut message, libc::MSG_CMSG_CLOEXEC) };
+    match received {
+        n if n < 0 => return Err(io::Error::last_os_error()),
+        0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+        _ => {}
+    }
+    let mut fd = None;
+    unsafe {
+        let mut header = libc::CMSG_FIRSTHDR(&message);
+        while !header.is_null() {
+            if (*header).cmsg_level == libc::SOL_SOCKET && (*header).cmsg_type == libc::SCM_RIGHTS {
+                let raw = std::ptr::read_unaligned(libc::CMSG_DATA(header).cast::<RawFd>());
+                fd = Some(OwnedFd::from_raw_fd(raw));
+            }
+            header = libc::CMSG_NXTHDR(&message, header);
+        }
+    }
+    Ok((data[0], fd))
+}
+
+/// Fetches the payload key through the companion. Only usable in `pre_app_specialize`.
+pub(crate) fn request_payload_key(api: &ZygiskApi) -> Result<PayloadKey, String> {
+    let fd = api.connect_companion();
+    if fd < 0 {
+        return Err("companion unavailable".to_string());
+    }
+    let mut stream = unsafe { UnixStream::from_raw_fd(fd) };
+    read_payload_key(&mut stream).map_err(|e| format!("companion: {}", e))
+}
+
+fn read_payload_key<S: Read + Write>(stream: &mut S) -> io::Result<PayloadKey> {
+    stream.write_all(&[REQUEST_PAYLOAD_KEY])?;
+    let mut status = [0u8; 1];
+    stream.read_exact(&mut status)?;
+    if status[0] != STATUS_OK {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no payload key"));
+    }
+    let mut key = PayloadKey::from_bytes([0; 32]);
+    stream.read_exact(key.as_bytes_mut())?;
+    Ok(key)
+}
+
+/// Reads `path` through the companion. Only usable in `pre_app_specialize`.
+pub(crate) fn request_file(api: &ZygiskApi, path: &str) -> io::Result<Vec<u8>> {
+    let mut memfd = open_file(api, path)?;
+    let mut data = Vec::new();
+    memfd.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Opens `path` through the companion, as a sealed memfd holding a copy. Only usable in
+/// `pre_app_specialize`.
+pub(crate) fn open_file(api: &ZygiskApi, path: &str) -> io::Result<File> {
+    let fd = api.connect_companion();
+    if fd < 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "companion unavailable",
+        ));
+    }
+    let mut stream = unsafe { UnixStream::from_raw_fd(fd) };
+    read_file_response(&mut stream, path)
+}
+
+/// Sends a [`REQUEST_FILE`] and returns the sealed memfd, positioned at the start.
+fn read_file_response(stream: &mut UnixStream, path: &str) -> io::Result<File> {
+    let mut request = Vec::with_capacity(5 + path.len());
+    request.push(REQUEST_FILE);
+    request.extend_from_slice(&(path.len() as u32).to_le_bytes());
+    request.extend_from_slice(path.as_bytes());
+    stream.write_all(&request)?;
+
+    match recv_with_fd(stream)? {
+        (STATUS_OK, Some(fd)) => {
+            let mut memfd = File::from(fd);
+            if !is_sealed(&memfd) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "companion sent an unsealed file",
+                ));
+            }
+            memfd.seek(SeekFrom::Start(0))?;
+            Ok(memfd)
+        }
+        (STATUS_OK, None) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "companion sent no file",
+        )),
+        (STATUS_DENIED, _) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "companion refused the path",
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "companion could not read the file",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+    fn sources(payload_key: &'static str) -> Sources<'static> {
+        Sources {
+            payload_key,
+            module_dir: FIXTURES,
+            config: "/nonexistent/loader.conf",
+            legacy_config: "/nonexistent/target",
+        }
+    }
+
+    fn round_trip(key_path: &'static str) -> io::Result<PayloadKey> {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        let companion = thread::spawn(move || serve(&mut server, &sources(key_path)));
+        let key = read_payload_key(&mut client);
+        companion.join().unwrap().unwrap();
+        key
+    }
+
+    fn fetch(sources: Sources<'static>, path: &str) -> io::Result<Vec<u8>> {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        let companion = thread::spawn(move || serve(&mut server, &sources));
+        let mut data = Vec::new();
+        let result = read_file_response(&mut client, path)
+            .and_then(|mut memfd| memfd.read_to_end(&mut data));
+        companion.join().unwrap().unwrap();
+        result.map(|_| data)
+    }
+
+    #[test]
+    fn serves_payload_key() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/payload.key");
+        let key = round_trip(path).unwrap();
+        assert_eq!(key.as_bytes(), &[0x42; 32]);
+    }
+
+    #[test]
+    fn reports_missing_key() {
+        let err = round_trip("/nonexistent/payload.key").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn serves_module_files_as_sealed_memfds() {
+        let path = format!("{}/payload.key", FIXTURES);
+        let data = fetch(sources("/nonexistent/payload.key"), &path).unwrap();
+        assert_eq!(data, fs::read(&path).unwrap());
+
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        let companion =
+            thread::spawn(move || serve(&mut server, &sources("/nonexistent/payload.key")));
+        let mut memfd = read_file_response(&mut client, &path).unwrap();
+        companion.join().unwrap().unwrap();
+        assert!(memfd.write_all(b"x").is_err());
+    }
+
+    #[test]
+    fn refuses_paths_outside_the_module() {
+        let key = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/payload.key");
+        let err = fetch(sources(key), key).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        let escape = format!("{}/../../Cargo.toml", FIXTURES);
+        let err = fetch(sources(key), &escape).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        let err = fetch(sources(key), &format!("{}/missing.so", FIXTURES))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn serves_absolute_payloads_named_by_the_config() {
+        let dir =
+            std::env::temp_dir().join(format!("zygiskloader-companion-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let payload = dir.join("libhook.so");
+        fs::write(&payload, b"\x7fELF").unwrap();
+        fs::write(dir.join("libhook.so.sig"), b"signature").unwrap();
+        fs::write(dir.join("libother.so"), b"\x7fELF").unwrap();
+        let config = dir.join("loader.conf");
+        fs::write(&config, format!("[a]\npayloads = {}\n", payload.display())).unwrap();
+        let config: &'static str = Box::leak(config.to_str().unwrap().to_string().into_boxed_str());
+        let key = dir.join("payload.key");
+        fs::write(&key, [0x42; 32]).unwrap();
+        let key: &'static str = Box::leak(key.to_str().unwrap().to_string().into_boxed_str());
+        let sources = || Sources {
+            payload_key: key,
+            module_dir: "/nonexistent",
+            config,
+            legacy_config: "/nonexistent/target",
+        };
+
+        let path = payload.to_str().unwrap();
+        assert_eq!(fetch(sources(), path).unwrap(), b"\x7fELF");
+        assert_eq!(
+            fetch(sources(), &format!("{}.sig", path)).unwrap(),
+            b"signature"
+        );
+        let other = dir.join("libother.so");
+        let err = fetch(sources(), other.to_str().unwrap()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        // A named payload swapped for a link to the key is refused.
+        fs::remove_file(&payload).unwrap();
+        std::os::unix::fs::symlink(key, &payload).unwrap();
+        let err = fetch(sources(), path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_requests() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        client.write_all(&[0xff]).unwrap();
+        let err = serve(&mut server, &sources("/nonexistent")).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,239 @@
+//! Compressed payloads.
+//!
+//! Payloads may be stored as zstd, gzip or xz files; the format is detected from the magic
+//! bytes, so the file name does not matter. Decompression output is capped so a malicious or
+//! corrupt archive cannot exhaust the memory of the forked process. Buffers that held decoded
+//! data are zeroed before they are freed.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use zeroize::{Zeroize, Zeroizing};
+
+/// Upper bound for a decompressed payload.
+pub(crate) const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Compression {
+    None,
+    Zstd,
+    Gzip,
+    Xz,
+}
+
+impl Compression {
+    pub(crate) fn detect(data: &[u8]) -> Compression {
+        if data.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else if data.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if data.starts_with(XZ_MAGIC) {
+            Compression::Xz
+        } else {
+            Compression::None
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::None => "uncompressed",
+            Compression::Zstd => "zstd",
+            Compression::Gzip => "gzip",
+            Compression::Xz => "xz",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum DecompressError {
+    /// The output would exceed the given limit in bytes.
+    TooLarge(usize),
+    Corrupt(Compression, String),
+}
+
+impl fmt::Display for DecompressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecompressError::TooLarge(limit) => {
+                write!(f, "decompressed size exceeds {} bytes", limit)
+            }
+            DecompressError::Corrupt(format, e) => write!(f, "corrupt {} data: {}", format, e),
+        }
+    }
+}
+
+impl std::error::Error for DecompressError {}
+
+/// A writer that refuses to grow past `limit` bytes. Zeroes every buffer it lets go of,
+/// including its output unless taken.
+struct CappedWriter {
+    out: Vec<u8>,
+    limit: usize,
+    exceeded: bool,
+}
+
+impl Write for CappedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.out.len() + buf.len();
+        if len > self.limit {
+            self.exceeded = true;
+            return Err(io::Error::other("size limit exceeded"));
+        }
+        if len > self.out.capacity() {
+            // Grown by hand: a reallocation would free the old buffer without zeroing it.
+            let capacity = len.max(self.out.capacity() * 2).min(self.limit);
+            let mut grown = Vec::with_capacity(capacity);
+            grown.extend_from_slice(&self.out);
+            self.out.zeroize();
+            self.out = grown;
+        }
+        self.out.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for CappedWriter {
+    fn drop(&mut self) {
+        self.out.zeroize();
+    }
+}
+
+/// Decompresses `data` if it carries a known magic, returning the format it was stored in.
+/// Uncompressed data is returned unchanged; compressed data is zeroed once decoded, since it may
+/// have just been decrypted.
+pub(crate) fn decompress(
+    data: Vec<u8>,
+    limit: usize,
+) -> Result<(Vec<u8>, Compression), DecompressError> {
+    let mut data = Zeroizing::new(data);
+    let format = Compression::detect(&data);
+    let mut writer = CappedWriter {
+        out: Vec::new(),
+        limit,
+        exceeded: false,
+    };
+    let result = match format {
+        Compression::None => return Ok((std::mem::take(&mut *data), format)),
+        Compression::Zstd => ruzstd::decoding::StreamingDecoder::new(data.as_slice())
+            .map_err(|e| e.to_string())
+            .and_then(|mut decoder| copy(&mut decoder, &mut writer)),
+        Compression::Gzip => copy(
+            &mut flate2::read::GzDecoder::new(data.as_slice()),
+            &mut writer,
+        ),
+        Compression::Xz => {
+            lzma_rs::xz_decompress(&mut data.as_slice(), &mut writer).map_err(|e| e.to_string())
+        }
+    };
+    match result {
+        Ok(()) => Ok((std::mem::take(&mut writer.out), format)),
+        Err(_) if writer.exceeded => Err(DecompressError::TooLarge(limit)),
+        Err(e) => Err(DecompressError::Corrupt(format, e)),
+    }
+}
+
+fn copy(reader: &mut impl Read, writer: &mut CappedWriter) -> Result<(), String> {
+    io::copy(reader, writer)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample() -> Vec<u8> {
+        let mut data = b"\x7fELF".to_vec();
+        for i in 0..4096u32 {
+            data.extend_from_slice(&(i % 97).to_le_bytes());
+        }
+        data
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zstd(data: &[u8]) -> Vec<u8> {
+        ruzstd::encoding::compress_to_vec(data, ruzstd::encoding::CompressionLevel::Fastest)
+    }
+
+    fn xz(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        lzma_rs::xz_compress(&mut &data[..], &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn detects_formats_by_magic() {
+        let data = sample();
+        assert_eq!(Compression::detect(&data), Compression::None);
+        assert_eq!(Compression::detect(&zstd(&data)), Compression::Zstd);
+        assert_eq!(Compression::detect(&gzip(&data)), Compression::Gzip);
+        assert_eq!(Compression::detect(&xz(&data)), Compression::Xz);
+        assert_eq!(Compression::detect(&[0x1f]), Compression::None);
+    }
+
+    #[test]
+    fn decompresses_every_format() {
+        let data = sample();
+        for (packed, format) in [
+            (data.clone(), Compression::None),
+            (zstd(&data), Compression::Zstd),
+            (gzip(&data), Compression::Gzip),
+            (xz(&data), Compression::Xz),
+        ] {
+            let (out, detected) = decompress(packed, MAX_DECOMPRESSED_SIZE).unwrap();
+            assert_eq!(detected, format);
+            assert_eq!(out, data);
+        }
+    }
+
+    #[test]
+    fn enforces_size_limit() {
+        let bomb = vec![0u8; 1024 * 1024];
+        for packed in [zstd(&bomb), gzip(&bomb), xz(&bomb)] {
+            assert!(matches!(
+                decompress(packed, 64 * 1024),
+                Err(DecompressError::TooLarge(65536))
+            ));
+        }
+    }
+
+    #[test]
+    fn reports_corrupt_data() {
+        let mut packed = gzip(&sample());
+        packed.truncate(packed.len() / 2);
+        assert!(matches!(
+            decompress(packed, MAX_DECOMPRESSED_SIZE),
+            Err(DecompressError::Corrupt(Compression::Gzip, _))
+        ));
+    }
+
+    #[test]
+    fn grows_output_within_the_limit() {
+        let mut writer = CappedWriter {
+            out: Vec::new(),
+            limit: 100,
+            exceeded: false,
+        };
+        for byte in 0..100u8 {
+            writer.write_all(&[byte]).unwrap();
+        }
+        assert_eq!(writer.out, (0..100u8).collect::<Vec<u8>>());
+        assert_eq!(writer.out.capacity(), 100);
+        assert!(writer.write_all(&[0]).is_err() && writer.exceeded);
+    }
+}
@@ -0,0 +1,937 @@
+//! Loader configuration.
+//!
+//! The config is a strict INI-like file. Every section is a target, named after the package it
+//! applies to, followed by `key = value` fields:
+//!
+//! ```text
+//! # comment
+//! [com.example.app]
+//! enabled  = true
+//! payloads = libbase.so, libfeature.so
+//! requires.libfeature.so = libbase.so
+//! match    = exact
+//! process  = com.example.app:remote
+//! users    = 0, 10
+//! exclude_kinds = isolated, app-zygote-isolated
+//! se_info  = platform
+//! top_app_only = true
+//! timing   = post-specialize
+//! inject   = memfd, proc-fd, cache-file
+//! arg.log_tag = "Example Hook"
+//! arg_delivery = context, env
+//! force_denylist_unmount = true
+//! ```
+//!
+//! Unknown keys, duplicate keys or sections, and malformed values are rejected with a
+//! [ParseError] carrying the 1-based line and column of the offending text.
+
+use std::fmt;
+use std::fs;
+use std::io;
+
+use crate::entry::arg_env_name;
+use crate::inject::InjectStrategy;
+use crate::matcher::{
+    IdRange, KindFilter, LaunchConditions, MatchMode, ProcessMatcher, SeInfoFilter, UidFilter,
+};
+use crate::process::{ProcessInfo, ProcessKind};
+
+/// Payload used when a target does not list any.
+pub(crate) const DEFAULT_PAYLOAD: &str = "payload.so";
+
+/// A payload of a target, in load order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct PayloadSpec {
+    /// File name relative to the config directory, or an absolute path.
+    pub name: String,
+    /// Payloads that must have loaded before this one. They always come earlier in the list.
+    pub requires: Vec<String>,
+}
+
+impl PayloadSpec {
+    pub(crate) fn new(name: &str) -> PayloadSpec {
+        PayloadSpec {
+            name: name.to_string(),
+            requires: Vec::new(),
+        }
+    }
+}
+
+impl AsRef<PayloadSpec> for PayloadSpec {
+    fn as_ref(&self) -> &PayloadSpec {
+        self
+    }
+}
+
+/// When the payloads of a target are loaded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum InjectTiming {
+    /// Load with zygote privileges, before the sandbox is applied to the process.
+    PreSpecialize,
+    /// Load with the app's own privileges, after specialization.
+    #[default]
+    PostSpecialize,
+}
+
+/// How the `arg.*` values of a target reach its payloads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ArgDelivery {
+    /// In the context passed to `zygisk_loader_main`.
+    pub context: bool,
+    /// As `ZYGISK_LOADER_ARG_<KEY>` environment variables, set before the first payload loads.
+    pub env: bool,
+}
+
+impl Default for ArgDelivery {
+    fn default() -> Self {
+        ArgDelivery {
+            context: true,
+            env: false,
+        }
+    }
+}
+
+/// A single `[package]` section of the config.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TargetConfig {
+    pub package: String,
+    pub enabled: bool,
+    pub payloads: Vec<PayloadSpec>,
+    /// Built from the `match` mode and `process` pattern. Defaults to every process of the package.
+    pub matcher: ProcessMatcher,
+    pub uids: UidFilter,
+    pub kinds: KindFilter,
+    pub se_info: SeInfoFilter,
+    pub launch: LaunchConditions,
+    pub timing: InjectTiming,
+    /// Injection strategies to try, in order.
+    pub inject: Vec<InjectStrategy>,
+    /// `arg.<key> = <value>` pairs, in declaration order.
+    pub args: Vec<(String, String)>,
+    pub arg_delivery: ArgDelivery,
+    /// Have the root implementation unmount its files from the process, denylisted or not.
+    pub force_denylist_unmount: bool,
+}
+
+impl TargetConfig {
+    fn new(package: &str) -> TargetConfig {
+        TargetConfig {
+            package: package.to_string(),
+            enabled: true,
+            payloads: vec![PayloadSpec::new(DEFAULT_PAYLOAD)],
+            matcher: ProcessMatcher::Package(package.to_string()),
+            uids: UidFilter::default(),
+            kinds: KindFilter::default(),
+            se_info: SeInfoFilter::default(),
+            launch: LaunchConditions::default(),
+            timing: InjectTiming::default(),
+            inject: InjectStrategy::DEFAULT_CHAIN.to_vec(),
+            args: Vec::new(),
+            arg_delivery: ArgDelivery::default(),
+            force_denylist_unmount: false,
+        }
+    }
+
+    pub(crate) fn matches(&self, process: &ProcessInfo) -> bool {
+        self.enabled
+            && self.matcher.matches(process)
+            && self.uids.matches(process)
+            && self.kinds.matches(process.kind)
+            && self.se_info.matches(&process.se_info)
+            && self.launch.matches(process)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct LoaderConfig {
+    pub targets: Vec<TargetConfig>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ParseErrorKind {
+    UnterminatedSection,
+    EmptySectionName,
+    DuplicateSection(String),
+    KeyOutsideSection,
+    MissingEquals,
+    EmptyKey,
+    UnknownKey(String),
+    DuplicateKey(String),
+    EmptyValue,
+    UnterminatedString,
+    InvalidEscape(char),
+    TrailingCharacters,
+    InvalidBool(String),
+    InvalidTiming(String),
+    InvalidInjectStrategy(String),
+    InvalidArgDelivery(String),
+    /// Two `arg.*` keys map to the same environment variable under `arg_delivery = env`.
+    ArgEnvCollision(String, String),
+    InvalidMatchMode(String),
+    InvalidRegex(String),
+    /// `process` is set, but the match mode only looks at the package.
+    UnusedProcess(MatchMode),
+    InvalidIdRange(String),
+    InvalidProcessKind(String),
+    UnknownPayload(String),
+    /// A payload requires one that is not listed before it.
+    DependencyOrder(String, String),
+}
+
+/// A syntax or validation error, located at a 1-based line and column.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: ParseErrorKind,
+}
+
+#[derive(Debug)]
+pub(crate) enum ConfigError {
+    Io(io::Error),
+    Parse(ParseError),
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorKind::UnterminatedSection => write!(f, "missing `]` after section name"),
+            ParseErrorKind::EmptySectionName => write!(f, "empty section name"),
+            ParseErrorKind::DuplicateSection(name) => write!(f, "duplicate section `{}`", name),
+            ParseErrorKind::KeyOutsideSection => write!(f, "key outside of any section"),
+            ParseErrorKind::MissingEquals => write!(f, "expected `key = value`"),
+            ParseErrorKind::EmptyKey => write!(f, "empty key"),
+            ParseErrorKind::UnknownKey(key) => write!(f, "unknown key `{}`", key),
+            ParseErrorKind::DuplicateKey(key) => write!(f, "duplicate key `{}`", key),
+            ParseErrorKind::EmptyValue => write!(f, "empty value"),
+            ParseErrorKind::UnterminatedString => write!(f, "unterminated string"),
+            ParseErrorKind::InvalidEscape(c) => write!(f, "invalid escape `\\{}`", c),
+            ParseErrorKind::TrailingCharacters => write!(f, "unexpected characters after value"),
+            ParseErrorKind::InvalidBool(v) => {
+                write!(f, "expected `true` or `false`, found `{}`", v)
+            }
+            ParseErrorKind::InvalidTiming(v) => write!(
+                f,
+                "expected `pre-specialize` or `post-specialize`, found `{}`",
+                v
+            ),
+            ParseErrorKind::InvalidInjectStrategy(v) => write!(
+                f,
+                "expected `memfd`, `proc-fd`, `cache-file` or `reflective`, found `{}`",
+                v
+            ),
+            ParseErrorKind::InvalidArgDelivery(v) => {
+                write!(f, "expected `context` or `env`, found `{}`", v)
+            }
+            ParseErrorKind::ArgEnvCollision(key, earlier) => write!(
+                f,
+                "`arg.{}` and `arg.{}` are both delivered as `{}`",
+                key,
+                earlier,
+                arg_env_name(key)
+            ),
+            ParseErrorKind::InvalidMatchMode(v) => write!(
+                f,
+                "expected `exact`, `main`, `package`, `glob`, `regex` or `shared-uid`, found `{}`",
+                v
+            ),
+            ParseErrorKind::InvalidRegex(e) => write!(f, "invalid regex: {}", e),
+            ParseErrorKind::UnusedProcess(mode) => write!(
+                f,
+                "`process` is ignored by `match = {}`, use `exact`, `glob` or `regex`",
+                mode
+            ),
+            ParseErrorKind::InvalidIdRange(v) => {
+                write!(f, "expected an id or `start-end` range, found `{}`", v)
+            }
+            ParseErrorKind::UnknownPayload(p) => write!(f, "`{}` is not in `payloads`", p),
+            ParseErrorKind::DependencyOrder(p, dep) => {
+                write!(
+                    f,
+                    "`{}` requires `{}`, which must be listed before it",
+                    p, dep
+                )
+            }
+            ParseErrorKind::InvalidProcessKind(v) => write!(
+                f,
+                "expected `app`, `system`, `isolated`, `app-zygote`, `app-zygote-isolated`, \
+                 `webview-zygote` or `sdk-sandbox`, found `{}`",
+                v
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.kind)
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "{}", e),
+            ConfigError::Parse(e) => write!(f, "parse error at {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+impl std::error::Error for ConfigError {}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> Self {
+        ConfigError::Io(e)
+    }
+}
+
+impl From<ParseError> for ConfigError {
+    fn from(e: ParseError) -> Self {
+        ConfigError::Parse(e)
+    }
+}
+
+impl LoaderConfig {
+    pub(crate) fn load(path: &str) -> Result<LoaderConfig, ConfigError> {
+        let text = fs::read_to_string(path)?;
+        Ok(LoaderConfig::parse(&text)?)
+    }
+
+    pub(crate) fn parse(text: &str) -> Result<LoaderConfig, ParseError> {
+        let mut config = LoaderConfig::default();
+        let mut section: Option<Section> = None;
+
+        for (index, raw_line) in text.lines().enumerate() {
+            let line_no = index + 1;
+            let indent = raw_line.len() - raw_line.trim_start().len();
+            let line = raw_line.trim();
+            let err = |column: usize, kind: ParseErrorKind| ParseError {
+                line: line_no,
+                column,
+                kind,
+            };
+
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if let Some(rest) = line.strip_prefix('[') {
+                let end = rest
+                    .find(']')
+                    .ok_or_else(|| err(indent + 1, ParseErrorKind::UnterminatedSection))?;
+                if !rest[end + 1..].trim().is_empty() {
+                    return Err(err(indent + end + 3, ParseErrorKind::TrailingCharacters));
+                }
+                let name = rest[..end].trim();
+                if name.is_empty() {
+                    return Err(err(indent + 2, ParseErrorKind::EmptySectionName));
+                }
+                if let Some(done) = section.take() {
+                    config.targets.push(done.finish()?);
+                }
+                if config.targets.iter().any(|t| t.package == name) {
+                    return Err(err(
+                        indent + 2,
+                        ParseErrorKind::DuplicateSection(name.to_string()),
+                    ));
+                }
+                section = Some(Section::new(name));
+                continue;
+            }
+
+            let eq = line
+                .find('=')
+                .ok_or_else(|| err(indent + 1, ParseErrorKind::MissingEquals))?;
+            let key = line[..eq].trim();
+            let value_start = eq + 1 + (line[eq + 1..].len() - line[eq + 1..].trim_start().len());
+            let value_column = indent + value_start + 1;
+
+            if key.is_empty() {
+                return Err(err(indent + 1, ParseErrorKind::EmptyKey));
+            }
+            let current = section
+                .as_mut()
+                .ok_or_else(|| err(indent + 1, ParseErrorKind::KeyOutsideSection))?;
+            if current.seen_keys.iter().any(|k| k == key) {
+                return Err(err(
+                    indent + 1,
+                    ParseErrorKind::DuplicateKey(key.to_string()),
+                ));
+            }
+            current.seen_keys.push(key.to_string());
+
+            let value = parse_value(&line[value_start..])
+                .map_err(|(offset, kind)| err(value_column + offset, kind))?;
+            let target = &mut current.target;
+
+            match key {
+                "enabled" | "top_app_only" | "first_start_only" | "skip_denylisted"
+                | "force_denylist_unmount" => {
+                    let flag = parse_bool(&value).map_err(|kind| err(value_column, kind))?;
+                    match key {
+                        "enabled" => target.enabled = flag,
+                        "top_app_only" => target.launch.top_app_only = flag,
+                        "first_start_only" => target.launch.first_start_only = flag,
+                        "force_denylist_unmount" => target.force_denylist_unmount = flag,
+                        _ => target.launch.skip_denylisted = flag,
+                    }
+                }
+                "payloads" => {
+                    let payloads = split_list(&value);
+                    if payloads.is_empty() {
+                        return Err(err(value_column, ParseErrorKind::EmptyValue));
+                    }
+                    target.payloads = payloads.into_iter().map(PayloadSpec::new).collect();
+                }
+                "users" | "app_ids" | "uids" => {
+                    let ranges = parse_id_ranges(&value).map_err(|kind| err(value_column, kind))?;
+                    match key {
+                        "users" => target.uids.users = ranges,
+                        "app_ids" => target.uids.app_ids = ranges,
+                        _ => target.uids.uids = ranges,
+                    }
+                }
+                "kinds" | "exclude_kinds" => {
+                    let kinds =
+                        parse_process_kinds(&value).map_err(|kind| err(value_column, kind))?;
+                    match key {
+                        "kinds" => target.kinds.include = kinds,
+                        _ => target.kinds.exclude = kinds,
+                    }
+                }
+                "se_info" => {
+                    let patterns = split_list(&value);
+                    if patterns.is_empty() {
+                        return Err(err(value_column, ParseErrorKind::EmptyValue));
+                    }
+                    target.se_info.patterns = patterns.into_iter().map(str::to_string).collect();
+                }
+                "match" => {
+                    current.mode = MatchMode::parse(&value).ok_or_else(|| {
+                        err(value_column, ParseErrorKind::InvalidMatchMode(value))
+                    })?;
+                    current.mode_at = (line_no, value_column);
+                }
+                "process" => current.process = Some((value, (line_no, value_column))),
+                "timing" => {
+                    target.timing = match value.as_str() {
+                        "pre-specialize" => InjectTiming::PreSpecialize,
+                        "post-specialize" => InjectTiming::PostSpecialize,
+                        _ => return Err(err(value_column, ParseErrorKind::InvalidTiming(value))),
+                    }
+                }
+                "inject" => {
+                    let names = split_list(&value);
+                    if names.is_empty() {
+                        return Err(err(value_column, ParseErrorKind::EmptyValue));
+                    }
+                    let mut chain = Vec::with_capacity(names.len());
+                    for name in names {
+                        let strategy = InjectStrategy::parse(name).ok_or_else(|| {
+                            err(
+                                value_column,
+                                ParseErrorKind::InvalidInjectStrategy(name.to_string()),
+                            )
+                        })?;
+                        if !chain.contains(&strategy) {
+                            chain.push(strategy);
+                        }
+                    }
+                    target.inject = chain;
+                }
+                "arg_delivery" => {
+                    let modes = split_list(&value);
+                    if modes.is_empty() {
+                        return Err(err(value_column, ParseErrorKind::EmptyValue));
+                    }
+                    let mut delivery = ArgDelivery {
+                        context: false,
+                        env: false,
+                    };
+                    for mode in modes {
+                        match mode {
+                            "context" => delivery.context = true,
+                            "env" => delivery.env = true,
+                            _ => {
+                                return Err(err(
+                                    value_column,
+                                    ParseErrorKind::InvalidArgDelivery(mode.to_string()),
+                                ))
+                            }
+                        }
+                    }
+                    target.arg_delivery = delivery;
+                }
+                _ => {
+                    if let Some(payload) = key.strip_prefix("requires.").filter(|p| !p.is_empty()) {
+                        let requires = split_list(&value).into_iter().map(str::to_string).collect();
+                        current.requires.push((
+                            payload.to_string(),
+                            requires,
+                            (line_no, indent + 1),
+                            (line_no, value_column),
+                        ));
+                    } else if let Some(arg) = key.strip_prefix("arg.").filter(|a| !a.is_empty()) {
+                        target.args.push((arg.to_string(), value));
+                        current.arg_keys.push((line_no, indent + 1));
+                    } else {
+                        return Err(err(indent + 1, ParseErrorKind::UnknownKey(key.to_string())));
+                    }
+                }
+            }
+        }
+
+        if let Some(done) = section {
+            config.targets.push(done.finish()?);
+        }
+        Ok(config)
+    }
+
+    /// Whether any enabled target needs the `packages.list` entries of the process.
+    pub(crate) fn needs_packages(&self) -> bool {
+        self.targets
+            .iter()
+            .any(|t| t.enabled && t.matcher.needs_packages())
+    }
+
+    /// Reads the pre-sectioned `target` file: one `<package> [payload ...]` per line.
+    pub(crate) fn load_legacy(path: &str) -> io::Result<LoaderConfig> {
+        let text = fs::read_to_string(path)?;
+        Ok(LoaderConfig::parse_legacy(&text))
+    }
+
+    pub(crate) fn parse_legacy(text: &str) -> LoaderConfig {
+        let mut config = LoaderConfig::default();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let Some(package) = fields.next() else {
+                continue;
+            };
+            let mut target = TargetConfig::new(package);
+            let payloads: Vec<PayloadSpec> = fields.map(PayloadSpec::new).collect();
+            if !payloads.is_empty() {
+                target.payloads = payloads;
+            }
+            config.targets.push(target);
+        }
+        config
+    }
+}
+
+/// 1-based line and column.
+type Location = (usize, usize);
+
+/// A target section being parsed. Fields that depend on each other are only resolved once the
+/// whole section has been read.
+struct Section {
+    target: TargetConfig,
+    /// Keys seen so far, to reject duplicates.
+    seen_keys: Vec<String>,
+    mode: MatchMode,
+    mode_at: Location,
+    process: Option<(String, Location)>,
+    /// Location of each key in `target.args`.
+    arg_keys: Vec<Location>,
+    /// `requires.<payload>` entries with the location of their key and value.
+    requires: Vec<(String, Vec<String>, Location, Location)>,
+}
+
+impl Section {
+    fn new(package: &str) -> Section {
+        Section {
+            target: TargetConfig::new(package),
+            seen_keys: Vec::new(),
+            mode: MatchMode::default(),
+            mode_at: (0, 0),
+            process: None,
+            arg_keys: Vec::new(),
+            requires: Vec::new(),
+        }
+    }
+
+    fn finish(mut self) -> Result<TargetConfig, ParseError> {
+        // Otherwise `process = com.foo:remote` would silently match every process of `com.foo`.
+        if let Some((_, (line, column))) = self.process {
+            if !self.mode.uses_pattern() {
+                return Err(ParseError {
+                    line,
+                    column,
+                    kind: ParseErrorKind::UnusedProcess(self.mode),
+                });
+            }
+        }
+        // `setenv` would otherwise let the later key silently overwrite the earlier one.
+        if self.target.arg_delivery.env {
+            let args = &self.target.args;
+            let names: Vec<String> = args.iter().map(|(k, _)| arg_env_name(k)).collect();
+            for (index, &(line, column)) in self.arg_keys.iter().enumerate() {
+                if let Some(earlier) = names[..index].iter().position(|n| *n == names[index]) {
+                    return Err(ParseError {
+                        line,
+                        column,
+                        kind: ParseErrorKind::ArgEnvCollision(
+                            args[index].0.clone(),
+                            args[earlier].0.clone(),
+                        ),
+                    });
+                }
+            }
+        }
+        let pattern = self.process.as_ref().map(|(p, _)| p.as_str());
+        self.target.matcher = ProcessMatcher::new(self.mode, &self.target.package, pattern)
+            .map_err(|e| {
+                let (line, column) = self.process.as_ref().map_or(self.mode_at, |(_, at)| *at);
+                ParseError {
+                    line,
+                    column,
+                    kind: ParseErrorKind::InvalidRegex(e.to_string()),
+                }
+            })?;
+
+        for (payload, requires, key_at, value_at) in self.requires {
+            let error = |(line, column): Location, kind| ParseError { line, column, kind };
+            let payloads = &mut self.target.payloads;
+            let index = payloads
+                .iter()
+                .position(|p| p.name == payload)
+                .ok_or_else(|| error(key_at, ParseErrorKind::UnknownPayload(payload.clone())))?;
+            for dependency in &requires {
+                // Dependencies must be listed earlier, which also rules out cycles.
+                match payloads.iter().position(|p| &p.name == dependency) {
+                    Some(dep_index) if dep_index < index => {}
+                    Some(_) => {
+                        return Err(error(
+                            value_at,
+                            ParseErrorKind::DependencyOrder(payload.clone(), dependency.clone()),
+                        ))
+                    }
+                    None => {
+                        return Err(error(
+                            value_at,
+                            ParseErrorKind::UnknownPayload(dependency.clone()),
+                        ))
+                    }
+                }
+            }
+            payloads[index].requires = requires;
+        }
+        Ok(self.target)
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, ParseErrorKind> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(ParseErrorKind::InvalidBool(value.to_string())),
+    }
+}
+
+/// Splits a comma-separated list, dropping empty items.
+fn split_list(value: &str) -> Vec<&str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+fn parse_id_ranges(value: &str) -> Result<Vec<IdRange>, ParseErrorKind> {
+    let items = split_list(value);
+    if items.is_empty() {
+        return Err(ParseErrorKind::EmptyValue);
+    }
+    items
+        .into_iter()
+        .map(|item| {
+            IdRange::parse(item).ok_or_else(|| ParseErrorKind::InvalidIdRange(item.to_string()))
+        })
+        .collect()
+}
+
+fn parse_process_kinds(value: &str) -> Result<Vec<ProcessKind>, ParseErrorKind> {
+    let items = split_list(value);
+    if items.is_empty() {
+        return Err(ParseErrorKind::EmptyValue);
+    }
+    items
+        .into_iter()
+        .map(|item| {
+            ProcessKind::parse(item)
+                .ok_or_else(|| ParseErrorKind::InvalidProcessKind(item.to_string()))
+        })
+        .collect()
+}
+
+/// Parses a (possibly quoted) value. Errors carry a 0-based offset into `raw`.
+fn parse_value(raw: &str) -> Result<String, (usize, ParseErrorKind)> {
+    let Some(quoted) = raw.strip_prefix('"') else {
+        // Unquoted values run to the end of the line, minus a trailing comment.
+        let value = match raw.find(" #") {
+            Some(comment) => raw[..comment].trim_end(),
+            None => raw.trim_end(),
+        };
+        if value.is_empty() {
+            return Err((0, ParseErrorKind::EmptyValue));
+        }
+        return Ok(value.to_string());
+    };
+
+    let mut value = String::new();
+    let mut chars = quoted.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                let rest = quoted[i + 1..].trim_start();
+                if !rest.is_empty() && !rest.starts_with('#') {
+                    let offset = raw.len() - rest.len();
+                    return Err((offset, ParseErrorKind::TrailingCharacters));
+                }
+                return Ok(value);
+            }
+            '\\' => match chars.next() {
+                Some((_, '"')) => value.push('"'),
+                Some((_, '\\')) => value.push('\\'),
+                Some((_, 'n')) => value.push('\n'),
+                Some((_, 't')) => value.push('\t'),
+                Some((j, other)) => return Err((j + 1, ParseErrorKind::InvalidEscape(other))),
+                None => break,
+            },
+            _ => value.push(c),
+        }
+    }
+    Err((0, ParseErrorKind::UnterminatedString))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_target_sections() {
+        let config = LoaderConfig::parse(
+            "# comment\n\
+             [com.example.app]\n\
+             requires.libfeature.so = libbase.so\n\
+             payloads = libbase.so, libfeature.so\n\
+             timing = pre-specialize\n\
+             arg.log_tag = \"Example \\\"Hook\\\"\" # trailing comment\n\
+             \n\
+             [com.other.app]\n\
+             enabled = false\n\
+             match = exact\n\
+             process = com.other.app:remote\n\
+             users = 10\n\
+             uids = 10000-19999\n\
+             exclude_kinds = isolated, sdk-sandbox\n\
+             se_info = platform, media\n\
+             skip_denylisted = true\n\
+             force_denylist_unmount = true\n",
+        )
+        .unwrap();
+
+        assert_eq!(config.targets.len(), 2);
+        let first = &config.targets[0];
+        assert_eq!(first.package, "com.example.app");
+        assert_eq!(
+            first.matcher,
+            ProcessMatcher::Package("com.example.app".to_string())
+        );
+        assert_eq!(
+            first.payloads,
+            [
+                PayloadSpec::new("libbase.so"),
+                PayloadSpec {
+                    name: "libfeature.so".to_string(),
+                    requires: vec!["libbase.so".to_string()],
+                },
+            ]
+        );
+        assert_eq!(first.timing, InjectTiming::PreSpecialize);
+        assert_eq!(
+            first.args,
+            [("log_tag".to_string(), "Example \"Hook\"".to_string())]
+        );
+
+        let second = &config.targets[1];
+        assert!(!second.enabled);
+        assert_eq!(second.payloads, [PayloadSpec::new(DEFAULT_PAYLOAD)]);
+        let named = |name: &str| ProcessInfo {
+            name: name.to_string(),
+            ..Default::default()
+        };
+        assert!(second.matcher.matches(&named("com.other.app:remote")));
+        assert!(!second.matcher.matches(&named("com.other.app")));
+        assert_eq!(second.uids.users, [IdRange { start: 10, end: 10 }]);
+        assert_eq!(
+            second.uids.uids,
+            [IdRange {
+                start: 10000,
+                end: 19999
+            }]
+        );
+        assert_eq!(second.se_info.patterns, ["platform", "media"]);
+        assert!(second.launch.skip_denylisted && !second.launch.top_app_only);
+        assert!(second.force_denylist_unmount && !first.force_denylist_unmount);
+        assert_eq!(
+            second.kinds.exclude,
+            [ProcessKind::Isolated, ProcessKind::SdkSandbox]
+        );
+    }
+
+    #[test]
+    fn reports_error_location() {
+        let err = LoaderConfig::parse("[com.example.app]\n  enabled = yes\n").unwrap_err();
+        assert_eq!(
+            err,
+            ParseError {
+                line: 2,
+                column: 13,
+                kind: ParseErrorKind::InvalidBool("yes".to_string()),
+            }
+        );
+
+        let err = LoaderConfig::parse("payloads = a.so\n").unwrap_err();
+        assert_eq!((err.line, err.column), (1, 1));
+        assert_eq!(err.kind, ParseErrorKind::KeyOutsideSection);
+
+        let err = LoaderConfig::parse("[a]\npayload = a.so\n").unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::UnknownKey("payload".to_string()));
+    }
+
+    #[test]
+    fn rejects_duplicates() {
+        let err = LoaderConfig::parse("[a]\n[a]\n").unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::DuplicateSection("a".to_string()));
+
+        let err = LoaderConfig::parse("[a]\ntiming = pre-specialize\ntiming = post-specialize\n")
+            .unwrap_err();
+        assert_eq!((err.line, err.column), (3, 1));
+        assert_eq!(err.kind, ParseErrorKind::DuplicateKey("timing".to_string()));
+    }
+
+    #[test]
+    fn reports_invalid_match() {
+        let err = LoaderConfig::parse("[a]\nmatch = contains\n").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 9));
+        assert_eq!(
+            err.kind,
+            ParseErrorKind::InvalidMatchMode("contains".to_string())
+        );
+
+        let err = LoaderConfig::parse("[a]\nprocess = a(\nmatch = regex\n[b]\n").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 11));
+        assert!(matches!(err.kind, ParseErrorKind::InvalidRegex(_)));
+
+        for mode in [
+            "",
+            "match = package\n",
+            "match = main\n",
+            "match = shared-uid\n",
+        ] {
+            let text = format!("[com.foo]\n{}process = com.foo:remote\n", mode);
+            let err = LoaderConfig::parse(&text).unwrap_err();
+            assert_eq!((err.line, err.column), (2 + !mode.is_empty() as usize, 11));
+            assert!(
+                matches!(err.kind, ParseErrorKind::UnusedProcess(_)),
+                "{}",
+                text
+            );
+        }
+        assert!(LoaderConfig::parse("[com.foo]\nprocess = com.foo:*\nmatch = glob\n").is_ok());
+    }
+
+    #[test]
+    fn reports_invalid_id_range() {
+        let err = LoaderConfig::parse("[a]\nusers = 0, ten\n").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 9));
+        assert_eq!(err.kind, ParseErrorKind::InvalidIdRange("ten".to_string()));
+    }
+
+    #[test]
+    fn parses_inject_chain() {
+        let config =
+            LoaderConfig::parse("[a]\ninject = cache-file, proc-fd, cache-file\n[b]\n").unwrap();
+        assert_eq!(
+            config.targets[0].inject,
+            [InjectStrategy::CacheFile, InjectStrategy::ProcFd]
+        );
+        assert_eq!(config.targets[1].inject, InjectStrategy::DEFAULT_CHAIN);
+
+        let err = LoaderConfig::parse("[a]\ninject = memfd, dlopen\n").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 10));
+        assert_eq!(
+            err.kind,
+            ParseErrorKind::InvalidInjectStrategy("dlopen".to_string())
+        );
+    }
+
+    #[test]
+    fn parses_arg_delivery() {
+        let config = LoaderConfig::parse(
+            "[a]\narg_delivery = env\n[b]\narg_delivery = env, context\n[c]\n",
+        )
+        .unwrap();
+        let modes: Vec<_> = config
+            .targets
+            .iter()
+            .map(|t| (t.arg_delivery.context, t.arg_delivery.env))
+            .collect();
+        assert_eq!(modes, [(false, true), (true, true), (true, false)]);
+
+        let err = LoaderConfig::parse("[a]\narg_delivery = context, argv\n").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 16));
+        assert_eq!(
+            err.kind,
+            ParseErrorKind::InvalidArgDelivery("argv".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_colliding_env_args() {
+        let text = "[a]\narg.log.tag = a\narg.log_tag = b\n";
+        assert_eq!(LoaderConfig::parse(text).unwrap().targets[0].args.len(), 2);
+
+        let err = LoaderConfig::parse(&format!("{}arg_delivery = env\n", text)).unwrap_err();
+        assert_eq!((err.line, err.column), (3, 1));
+        assert_eq!(
+            err.kind,
+            ParseErrorKind::ArgEnvCollision("log_tag".to_string(), "log.tag".to_string())
+        );
+        assert_eq!(
+            err.kind.to_string(),
+            "`arg.log_tag` and `arg.log.tag` are both delivered as `ZYGISK_LOADER_ARG_LOG_TAG`"
+        );
+    }
+
+    #[test]
+    fn validates_payload_dependencies() {
+        let err = LoaderConfig::parse("[a]\npayloads = a.so\nrequires.b.so = a.so\n").unwrap_err();
+        assert_eq!((err.line, err.column), (3, 1));
+        assert_eq!(err.kind, ParseErrorKind::UnknownPayload("b.so".to_string()));
+
+        let err =
+            LoaderConfig::parse("[a]\npayloads = a.so, b.so\nrequires.a.so = b.so\n").unwrap_err();
+        assert_eq!((err.line, err.column), (3, 17));
+        assert_eq!(
+            err.kind,
+            ParseErrorKind::DependencyOrder("a.so".to_string(), "b.so".to_string())
+        );
+
+        let err =
+            LoaderConfig::parse("[a]\npayloads = a.so, b.so\nrequires.b.so = c.so\n").unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::UnknownPayload("c.so".to_string()));
+    }
+
+    #[test]
+    fn rejects_unterminated_string() {
+        let err = LoaderConfig::parse("[a]\narg.x = \"abc\n").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 9));
+        assert_eq!(err.kind, ParseErrorKind::UnterminatedString);
+    }
+}
@@ -0,0 +1,159 @@
+//! Encrypted-at-rest payloads.
+//!
+//! An encrypted payload is a small container around the stored (possibly compressed) payload:
+//!
+//! ```text
+//! "ZLCRYPT1" | 12-byte nonce | ChaCha20-Poly1305 ciphertext and tag
+//! ```
+//!
+//! The magic and nonce are authenticated as associated data. The 32-byte key lives in its own
+//! file outside the config directory, as 64 hex characters.
+
+use std::fmt;
+use std::fs;
+use std::io;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use zeroize::Zeroizing;
+
+use crate::signature::decode_hex;
+
+pub(crate) const ENCRYPTED_MAGIC: &[u8; 8] = b"ZLCRYPT1";
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = ENCRYPTED_MAGIC.len() + NONCE_LEN;
+const TAG_LEN: usize = 16;
+
+/// A payload decryption key. Wiped from memory when dropped.
+pub(crate) struct PayloadKey(Zeroizing<[u8; 32]>);
+
+impl PayloadKey {
+    pub(crate) fn from_bytes(bytes: [u8; 32]) -> PayloadKey {
+        PayloadKey(Zeroizing::new(bytes))
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub(crate) fn as_bytes_mut(&mut self) -> &mut [u8; 32] {
+        &mut self.0
+    }
+
+    /// Parses a key file: 64 hex characters, surrounding whitespace ignored.
+    pub(crate) fn parse(text: &str) -> Option<PayloadKey> {
+        decode_hex::<32>(text.trim()).map(PayloadKey::from_bytes)
+    }
+
+    pub(crate) fn load(path: &str) -> io::Result<PayloadKey> {
+        let text = Zeroizing::new(fs::read_to_string(path)?);
+        PayloadKey::parse(&text)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a hex 32-byte key"))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum CryptError {
+    /// Shorter than the header and tag.
+    Truncated,
+    /// Wrong key, or the container was modified.
+    Authentication,
+}
+
+impl fmt::Display for CryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptError::Truncated => write!(f, "encrypted payload is truncated"),
+            CryptError::Authentication => {
+                write!(f, "decryption failed (wrong key or corrupted file)")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CryptError {}
+
+pub(crate) fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(ENCRYPTED_MAGIC)
+}
+
+/// Decrypts a container produced by [`encrypt`].
+pub(crate) fn decrypt(data: &[u8], key: &PayloadKey) -> Result<Vec<u8>, CryptError> {
+    if data.len() < HEADER_LEN + TAG_LEN || !is_encrypted(data) {
+        return Err(CryptError::Truncated);
+    }
+    let (header, ciphertext) = data.split_at(HEADER_LEN);
+    let nonce = Nonce::from_slice(&header[ENCRYPTED_MAGIC.len()..]);
+    ChaCha20Poly1305::new(Key::from_slice(key.as_bytes()))
+        .decrypt(
+            nonce,
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map_err(|_| CryptError::Authentication)
+}
+
+/// Wraps `data` in an encrypted container. The nonce must never be reused with the same key.
+#[cfg(any(test, feature = "host-tools"))]
+pub(crate) fn encrypt(data: &[u8], key: &PayloadKey, nonce: &[u8; NONCE_LEN]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_LEN + data.len() + TAG_LEN);
+    out.extend_from_slice(ENCRYPTED_MAGIC);
+    out.extend_from_slice(nonce);
+    let ciphertext = ChaCha20Poly1305::new(Key::from_slice(key.as_bytes()))
+        .encrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: data,
+                aad: &out,
+            },
+        )
+        .expect("payload too large to encrypt");
+    out.extend_from_slice(&ciphertext);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encrypt_and_decrypt_round_trip() {
+        let key = PayloadKey::from_bytes([5; 32]);
+        let payload = b"\x7fELF payload bytes";
+        let sealed = encrypt(payload, &key, &[1; NONCE_LEN]);
+        assert!(is_encrypted(&sealed));
+        assert!(!sealed.windows(payload.len()).any(|w| w == payload));
+        assert_eq!(decrypt(&sealed, &key).unwrap(), payload);
+
+        let wrong = PayloadKey::from_bytes([6; 32]);
+        assert_eq!(decrypt(&sealed, &wrong), Err(CryptError::Authentication));
+    }
+
+    #[test]
+    fn rejects_tampered_containers() {
+        let key = PayloadKey::from_bytes([5; 32]);
+        let sealed = encrypt(b"\x7fELF payload bytes", &key, &[1; NONCE_LEN]);
+
+        let mut flipped = sealed.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert_eq!(decrypt(&flipped, &key), Err(CryptError::Authentication));
+
+        let mut renonced = sealed.clone();
+        renonced[ENCRYPTED_MAGIC.len()] ^= 1;
+        assert_eq!(decrypt(&renonced, &key), Err(CryptError::Authentication));
+
+        assert_eq!(
+            decrypt(&sealed[..HEADER_LEN + 4], &key),
+            Err(CryptError::Truncated)
+        );
+    }
+
+    #[test]
+    fn parses_key_files() {
+        let key = PayloadKey::parse(&format!("{}\n", "ab".repeat(32))).unwrap();
+        assert_eq!(key.as_bytes(), &[0xab; 32]);
+        assert!(PayloadKey::parse("abcd").is_none());
+    }
+}
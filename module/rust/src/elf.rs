@@ -0,0 +1,557 @@
+//! Pre-flight inspection of payload ELF images.
+//!
+//! A buffered payload is checked before it is written anywhere or handed to the linker, so that
+//! truncated copies, builds for the wrong ABI and non-ELF files are rejected with a clear reason
+//! instead of a `dlerror()` after the fact.
+
+use std::fmt;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_DYN: u16 = 3;
+pub(crate) const PT_LOAD: u32 = 1;
+pub(crate) const PT_DYNAMIC: u32 = 2;
+
+const DT_NULL: u64 = 0;
+const DT_HASH: u64 = 4;
+const DT_STRTAB: u64 = 5;
+const DT_SYMTAB: u64 = 6;
+const DT_GNU_HASH: u64 = 0x6fff_fef5;
+
+const SHN_UNDEF: u16 = 0;
+const SHN_LORESERVE: u16 = 0xff00;
+const STB_GLOBAL: u8 = 1;
+const STB_WEAK: u8 = 2;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+const EM_386: u16 = 3;
+const EM_ARM: u16 = 40;
+const EM_X86_64: u16 = 62;
+const EM_AARCH64: u16 = 183;
+const EM_RISCV: u16 = 243;
+
+/// ELF class and machine expected for an ART instruction set name.
+fn expected_target(instruction_set: &str) -> Option<(u8, u16)> {
+    match instruction_set {
+        "arm" => Some((ELFCLASS32, EM_ARM)),
+        "arm64" => Some((ELFCLASS64, EM_AARCH64)),
+        "x86" => Some((ELFCLASS32, EM_386)),
+        "x86_64" => Some((ELFCLASS64, EM_X86_64)),
+        "riscv64" => Some((ELFCLASS64, EM_RISCV)),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ElfError {
+    /// Shorter than the structure being read, e.g. a truncated copy.
+    Truncated(&'static str),
+    BadMagic,
+    BadClass(u8),
+    NotLittleEndian,
+    /// Built for another ABI: (found class, found machine, process instruction set).
+    WrongMachine(u8, u16, String),
+    NotSharedObject(u16),
+    BadProgramHeaderSize(u16),
+    NoLoadSegment,
+    NoDynamicSegment,
+    /// A `PT_LOAD` segment with inconsistent alignment, by program header index.
+    BadAlignment(usize),
+    /// A `PT_LOAD` segment extending past the end of the file, by program header index.
+    SegmentOutOfBounds(usize),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::Truncated(what) => write!(f, "truncated ELF: {} out of bounds", what),
+            ElfError::BadMagic => write!(f, "not an ELF file"),
+            ElfError::BadClass(class) => write!(f, "invalid ELF class {}", class),
+            ElfError::NotLittleEndian => write!(f, "not a little-endian ELF"),
+            ElfError::WrongMachine(class, machine, isa) => write!(
+                f,
+                "built for ELF class {} machine {}, process ABI is {}",
+                class, machine, isa
+            ),
+            ElfError::NotSharedObject(e_type) => {
+                write!(f, "ELF type {} is not a shared object (ET_DYN)", e_type)
+            }
+            ElfError::BadProgramHeaderSize(size) => {
+                write!(f, "unexpected program header size {}", size)
+            }
+            ElfError::NoLoadSegment => write!(f, "no PT_LOAD segment"),
+            ElfError::NoDynamicSegment => write!(f, "no PT_DYNAMIC segment"),
+            ElfError::BadAlignment(index) => {
+                write!(f, "PT_LOAD segment {} is not consistently aligned", index)
+            }
+            ElfError::SegmentOutOfBounds(index) => {
+                write!(
+                    f,
+                    "PT_LOAD segment {} extends past the end of the file",
+                    index
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+/// The parts of a validated image that later stages care about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ElfSummary {
+    pub class: u8,
+    pub machine: u16,
+    pub load_segments: usize,
+}
+
+/// A program header, widened to 64 bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ProgramHeader {
+    pub p_type: u32,
+    pub p_flags: u32,
+    pub p_offset: u64,
+    pub p_vaddr: u64,
+    pub p_filesz: u64,
+    pub p_memsz: u64,
+    pub p_align: u64,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    is_64: bool,
+}
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&self, offset: u64, what: &'static str) -> Result<[u8; N], ElfError> {
+        let start = usize::try_from(offset).map_err(|_| ElfError::Truncated(what))?;
+        self.data
+            .get(start..start.checked_add(N).ok_or(ElfError::Truncated(what))?)
+            .map(|b| b.try_into().unwrap())
+            .ok_or(ElfError::Truncated(what))
+    }
+
+    fn u16(&self, offset: u64, what: &'static str) -> Result<u16, ElfError> {
+        Ok(u16::from_le_bytes(self.bytes(offset, what)?))
+    }
+
+    fn u32(&self, offset: u64, what: &'static str) -> Result<u32, ElfError> {
+        Ok(u32::from_le_bytes(self.bytes(offset, what)?))
+    }
+
+    fn u64(&self, offset: u64, what: &'static str) -> Result<u64, ElfError> {
+        Ok(u64::from_le_bytes(self.bytes(offset, what)?))
+    }
+
+    /// Reads an address-sized field: 4 bytes for ELF32, 8 for ELF64.
+    fn addr(&self, offset: u64, what: &'static str) -> Result<u64, ElfError> {
+        if self.is_64 {
+            self.u64(offset, what)
+        } else {
+            self.u32(offset, what).map(u64::from)
+        }
+    }
+
+    fn program_header(&self, offset: u64) -> Result<ProgramHeader, ElfError> {
+        const WHAT: &str = "program header";
+        Ok(if self.is_64 {
+            ProgramHeader {
+                p_type: self.u32(offset, WHAT)?,
+                p_flags: self.u32(offset + 4, WHAT)?,
+                p_offset: self.u64(offset + 8, WHAT)?,
+                p_vaddr: self.u64(offset + 16, WHAT)?,
+                p_filesz: self.u64(offset + 32, WHAT)?,
+                p_memsz: self.u64(offset + 40, WHAT)?,
+                p_align: self.u64(offset + 48, WHAT)?,
+            }
+        } else {
+            ProgramHeader {
+                p_type: self.u32(offset, WHAT)?,
+                p_flags: self.u32(offset + 24, WHAT)?,
+                p_offset: self.u32(offset + 4, WHAT)?.into(),
+                p_vaddr: self.u32(offset + 8, WHAT)?.into(),
+                p_filesz: self.u32(offset + 16, WHAT)?.into(),
+                p_memsz: self.u32(offset + 20, WHAT)?.into(),
+                p_align: self.u32(offset + 28, WHAT)?.into(),
+            }
+        })
+    }
+}
+
+/// Checks the identification bytes and returns a reader for the rest of the image.
+fn reader(data: &[u8]) -> Result<Reader<'_>, ElfError> {
+    if data.len() < 16 {
+        return Err(ElfError::Truncated("ELF identification"));
+    }
+    if &data[..4] != ELF_MAGIC {
+        return Err(ElfError::BadMagic);
+    }
+    let is_64 = match data[4] {
+        ELFCLASS32 => false,
+        ELFCLASS64 => true,
+        class => return Err(ElfError::BadClass(class)),
+    };
+    if data[5] != ELFDATA2LSB {
+        return Err(ElfError::NotLittleEndian);
+    }
+    Ok(Reader { data, is_64 })
+}
+
+fn read_program_headers(reader: &Reader) -> Result<Vec<ProgramHeader>, ElfError> {
+    let (e_phoff, e_phentsize, e_phnum) = if reader.is_64 {
+        (
+            reader.addr(32, "ELF header")?,
+            reader.u16(54, "ELF header")?,
+            reader.u16(56, "ELF header")?,
+        )
+    } else {
+        (
+            reader.addr(28, "ELF header")?,
+            reader.u16(42, "ELF header")?,
+            reader.u16(44, "ELF header")?,
+        )
+    };
+    let expected_phentsize = if reader.is_64 { 56 } else { 32 };
+    if e_phentsize != expected_phentsize {
+        return Err(ElfError::BadProgramHeaderSize(e_phentsize));
+    }
+    (0..u64::from(e_phnum))
+        .map(|index| {
+            // A hostile e_phoff must not overflow, here or in the field offsets.
+            let offset = index
+                .checked_mul(u64::from(e_phentsize))
+                .and_then(|offset| offset.checked_add(e_phoff))
+                .filter(|offset| offset.checked_add(u64::from(e_phentsize)).is_some())
+                .ok_or(ElfError::Truncated("program header"))?;
+            reader.program_header(offset)
+        })
+        .collect()
+}
+
+/// Translates a virtual address to a file offset through the `PT_LOAD` segments.
+fn file_offset(headers: &[ProgramHeader], vaddr: u64) -> Option<u64> {
+    headers
+        .iter()
+        .filter(|h| h.p_type == PT_LOAD)
+        .find(|h| vaddr >= h.p_vaddr && vaddr - h.p_vaddr < h.p_filesz)
+        .and_then(|h| h.p_offset.checked_add(vaddr - h.p_vaddr))
+}
+
+/// Offsets of the dynamic symbol table, string table and hash tables, when present.
+#[derive(Default)]
+struct DynamicTables {
+    symtab: Option<u64>,
+    strtab: Option<u64>,
+    hash: Option<u64>,
+    gnu_hash: Option<u64>,
+}
+
+fn dynamic_tables(reader: &Reader, headers: &[ProgramHeader]) -> Result<DynamicTables, ElfError> {
+    const WHAT: &str = "dynamic section";
+    let mut tables = DynamicTables::default();
+    let Some(dynamic) = headers.iter().find(|h| h.p_type == PT_DYNAMIC) else {
+        return Ok(tables);
+    };
+    let entry_size = if reader.is_64 { 16 } else { 8 };
+    for index in 0..dynamic.p_filesz / entry_size {
+        let offset = dynamic
+            .p_offset
+            .checked_add(index * entry_size)
+            .ok_or(ElfError::Truncated(WHAT))?;
+        let tag = reader.addr(offset, WHAT)?;
+        let value = reader.addr(offset + entry_size / 2, WHAT)?;
+        let table = match tag {
+            DT_NULL => break,
+            DT_SYMTAB => &mut tables.symtab,
+            DT_STRTAB => &mut tables.strtab,
+            DT_HASH => &mut tables.hash,
+            DT_GNU_HASH => &mut tables.gnu_hash,
+            _ => continue,
+        };
+        *table = Some(file_offset(headers, value).ok_or(ElfError::Truncated(WHAT))?);
+    }
+    Ok(tables)
+}
+
+impl Reader<'_> {
+    /// The NUL-terminated string at `offset`, without the NUL.
+    fn string(&self, offset: u64, what: &'static str) -> Result<&[u8], ElfError> {
+        let start = usize::try_from(offset).map_err(|_| ElfError::Truncated(what))?;
+        let rest = self.data.get(start..).ok_or(ElfError::Truncated(what))?;
+        let len = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or(ElfError::Truncated(what))?;
+        Ok(&rest[..len])
+    }
+
+    /// The name of dynamic symbol `index` when the image defines and exports it as a function
+    /// or an object. Anything `dlsym` could resolve elsewhere (TLS, IFUNC, absolute) is skipped.
+    fn exported_name(&self, tables: &DynamicTables, index: u64) -> Result<Option<&[u8]>, ElfError> {
+        const WHAT: &str = "dynamic symbol";
+        let (Some(symtab), Some(strtab)) = (tables.symtab, tables.strtab) else {
+            return Ok(None);
+        };
+        let (size, info, shndx) = if self.is_64 { (24, 4, 6) } else { (16, 12, 14) };
+        let offset = index
+            .checked_mul(size)
+            .and_then(|offset| offset.checked_add(symtab))
+            .ok_or(ElfError::Truncated(WHAT))?;
+        let name = self.u32(offset, WHAT)?;
+        let [info] = self.bytes::<1>(offset + info, WHAT)?;
+        let shndx = self.u16(offset + shndx, WHAT)?;
+        let exported = matches!(info >> 4, STB_GLOBAL | STB_WEAK)
+            && matches!(info & 0xf, STT_OBJECT | STT_FUNC)
+            && shndx != SHN_UNDEF
+            && shndx < SHN_LORESERVE;
+        if !exported {
+            return Ok(None);
+        }
+        let name = self.string(strtab + u64::from(name), WHAT)?;
+        Ok((!name.is_empty()).then_some(name))
+    }
+}
+
+/// The name of one function or object the image exports from its own dynamic symbol table, found
+/// through `DT_GNU_HASH` or `DT_HASH`. `None` when it exports nothing.
+pub(crate) fn first_export(data: &[u8]) -> Result<Option<Vec<u8>>, ElfError> {
+    const WHAT: &str = "hash table";
+    let reader = reader(data)?;
+    let headers = read_program_headers(&reader)?;
+    let tables = dynamic_tables(&reader, &headers)?;
+    if let Some(table) = tables.gnu_hash {
+        let nbuckets = u64::from(reader.u32(table, WHAT)?);
+        let symoffset = u64::from(reader.u32(table + 4, WHAT)?);
+        let bloom_size = u64::from(reader.u32(table + 8, WHAT)?);
+        let word = if reader.is_64 { 8 } else { 4 };
+        let buckets = bloom_size
+            .checked_mul(word)
+            .and_then(|bloom| bloom.checked_add(table + 16))
+            .ok_or(ElfError::Truncated(WHAT))?;
+        let chains = buckets
+            .checked_add(nbuckets * 4)
+            .ok_or(ElfError::Truncated(WHAT))?;
+        for bucket in 0..nbuckets {
+            let mut index = u64::from(reader.u32(buckets + bucket * 4, WHAT)?);
+            if index < symoffset {
+                continue;
+            }
+            loop {
+                if let Some(name) = reader.exported_name(&tables, index)? {
+                    return Ok(Some(name.to_vec()));
+                }
+                let chain = reader.u32(chains + (index - symoffset) * 4, WHAT)?;
+                if chain & 1 != 0 {
+                    break;
+                }
+                index += 1;
+            }
+        }
+    } else if let Some(table) = tables.hash {
+        let nchain = u64::from(reader.u32(table + 4, WHAT)?);
+        for index in 1..nchain {
+            if let Some(name) = reader.exported_name(&tables, index)? {
+                return Ok(Some(name.to_vec()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The program headers of an image that passed [validate].
+pub(crate) fn program_headers(data: &[u8]) -> Result<Vec<ProgramHeader>, ElfError> {
+    read_program_headers(&reader(data)?)
+}
+
+/// Checks that `data` is a little-endian `ET_DYN` image for `instruction_set` with sane program
+/// headers. The ABI check is skipped when the instruction set is unknown.
+pub(crate) fn validate(data: &[u8], instruction_set: &str) -> Result<ElfSummary, ElfError> {
+    let reader = reader(data)?;
+    let class = data[4];
+    let e_type = reader.u16(16, "ELF header")?;
+    let machine = reader.u16(18, "ELF header")?;
+
+    if let Some((want_class, want_machine)) = expected_target(instruction_set) {
+        if (class, machine) != (want_class, want_machine) {
+            return Err(ElfError::WrongMachine(
+                class,
+                machine,
+                instruction_set.to_string(),
+            ));
+        }
+    }
+    if e_type != ET_DYN {
+        return Err(ElfError::NotSharedObject(e_type));
+    }
+
+    let mut load_segments = 0;
+    let mut has_dynamic = false;
+    for (index, phdr) in read_program_headers(&reader)?.into_iter().enumerate() {
+        match phdr.p_type {
+            PT_LOAD => {
+                load_segments += 1;
+                let align = phdr.p_align;
+                if align > 1
+                    && (!align.is_power_of_two() || phdr.p_offset % align != phdr.p_vaddr % align)
+                {
+                    return Err(ElfError::BadAlignment(index));
+                }
+                if phdr.p_filesz > phdr.p_memsz
+                    || phdr
+                        .p_offset
+                        .checked_add(phdr.p_filesz)
+                        .is_none_or(|end| end > data.len() as u64)
+                {
+                    return Err(ElfError::SegmentOutOfBounds(index));
+                }
+            }
+            PT_DYNAMIC => has_dynamic = true,
+            _ => {}
+        }
+    }
+    if load_segments == 0 {
+        return Err(ElfError::NoLoadSegment);
+    }
+    if !has_dynamic {
+        return Err(ElfError::NoDynamicSegment);
+    }
+
+    Ok(ElfSummary {
+        class,
+        machine,
+        load_segments,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A minimal ELF64 x86_64 shared object: header, two program headers, and padding up to
+    /// the end of the loaded segment.
+    fn sample() -> Vec<u8> {
+        let mut elf = vec![0u8; 0x1000];
+        elf[..4].copy_from_slice(ELF_MAGIC);
+        elf[4] = ELFCLASS64;
+        elf[5] = ELFDATA2LSB;
+        elf[6] = 1;
+        elf[16..18].copy_from_slice(&ET_DYN.to_le_bytes());
+        elf[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+        elf[32..40].copy_from_slice(&64u64.to_le_bytes());
+        elf[54..56].copy_from_slice(&56u16.to_le_bytes());
+        elf[56..58].copy_from_slice(&2u16.to_le_bytes());
+
+        let mut phdr = |index: usize, p_type: u32, offset: u64, size: u64, align: u64| {
+            let base = 64 + index * 56;
+            elf[base..base + 4].copy_from_slice(&p_type.to_le_bytes());
+            elf[base + 8..base + 16].copy_from_slice(&offset.to_le_bytes());
+            elf[base + 16..base + 24].copy_from_slice(&offset.to_le_bytes());
+            elf[base + 32..base + 40].copy_from_slice(&size.to_le_bytes());
+            elf[base + 40..base + 48].copy_from_slice(&size.to_le_bytes());
+            elf[base + 48..base + 56].copy_from_slice(&align.to_le_bytes());
+        };
+        phdr(0, PT_LOAD, 0, 0x1000, 0x1000);
+        phdr(1, PT_DYNAMIC, 0x200, 0x100, 8);
+        elf
+    }
+
+    #[test]
+    fn accepts_valid_shared_object() {
+        assert_eq!(
+            validate(&sample(), "x86_64"),
+            Ok(ElfSummary {
+                class: ELFCLASS64,
+                machine: EM_X86_64,
+                load_segments: 1,
+            })
+        );
+        // Unknown instruction sets skip the ABI check.
+        assert!(validate(&sample(), "").is_ok());
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn accepts_own_test_binary() {
+        // The test harness is a PIE, i.e. ET_DYN, on x86_64 Linux.
+        let exe = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        assert!(validate(&exe, "x86_64").is_ok());
+        assert!(matches!(
+            validate(&exe, "arm64"),
+            Err(ElfError::WrongMachine(ELFCLASS64, EM_X86_64, _))
+        ));
+    }
+
+    #[test]
+    fn rejects_non_elf_and_truncated_files() {
+        assert_eq!(
+            validate(b"#!/system/bin/sh\necho hi\n", "arm64"),
+            Err(ElfError::BadMagic)
+        );
+        assert_eq!(
+            validate(&sample()[..10], "x86_64"),
+            Err(ElfError::Truncated("ELF identification"))
+        );
+        assert_eq!(
+            validate(&sample()[..100], "x86_64"),
+            Err(ElfError::Truncated("program header"))
+        );
+        assert_eq!(
+            validate(&sample()[..0x800], "x86_64"),
+            Err(ElfError::SegmentOutOfBounds(0))
+        );
+
+        let mut far = sample();
+        far[32..40].copy_from_slice(&(u64::MAX - 60).to_le_bytes());
+        assert_eq!(
+            validate(&far, "x86_64"),
+            Err(ElfError::Truncated("program header"))
+        );
+    }
+
+    #[test]
+    fn finds_an_export_through_the_hash_table() {
+        assert_eq!(first_export(&sample()), Ok(None));
+        #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+        {
+            let payload = crate::reflective::test::sample_payload();
+            let name = first_export(payload).unwrap().unwrap();
+            let name = std::ffi::CString::new(name).unwrap();
+            let image = crate::reflective::load(payload).unwrap();
+            assert!(image.symbol(&name).is_some(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_abi_and_type() {
+        assert_eq!(
+            validate(&sample(), "arm"),
+            Err(ElfError::WrongMachine(
+                ELFCLASS64,
+                EM_X86_64,
+                "arm".to_string()
+            ))
+        );
+
+        let mut executable = sample();
+        executable[16] = 2;
+        assert_eq!(
+            validate(&executable, "x86_64"),
+            Err(ElfError::NotSharedObject(2))
+        );
+    }
+
+    #[test]
+    fn rejects_misaligned_segments() {
+        let mut elf = sample();
+        // p_vaddr of the PT_LOAD segment no longer congruent to p_offset.
+        elf[64 + 16..64 + 24].copy_from_slice(&0x10u64.to_le_bytes());
+        assert_eq!(validate(&elf, "x86_64"), Err(ElfError::BadAlignment(0)));
+
+        let mut elf = sample();
+        elf[64 + 48..64 + 56].copy_from_slice(&0x300u64.to_le_bytes());
+        assert_eq!(validate(&elf, "x86_64"), Err(ElfError::BadAlignment(0)));
+    }
+}
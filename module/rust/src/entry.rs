@@ -0,0 +1,323 @@
+//! Payload entry points.
+//!
+//! After a payload is loaded, the loader calls its `zygisk_loader_main` export, if any, with a
+//! [ZygiskLoaderContext] describing the process. The public definitions live in
+//! `include/zygisk_loader.rs` and `include/zygisk_loader.h`, shared with payload authors.
+//!
+//! Payloads loaded after specialization also get their `JNI_OnLoad` called, as
+//! `System.loadLibrary` would. The app's class loader does not exist yet at that point, so only
+//! boot classpath classes can be found from there.
+//!
+//! The `arg.*` values of the target travel in the context, in the environment, or both,
+//! depending on its `arg_delivery`.
+
+use std::ffi::{c_int, c_void, CString};
+
+use jni::sys::{jint, JNI_ERR, JNI_VERSION_1_2, JNI_VERSION_1_4, JNI_VERSION_1_6};
+use jni::JavaVM;
+
+use crate::config::TargetConfig;
+use crate::inject::LoadedLibrary;
+use crate::process::ProcessInfo;
+
+include!("../include/zygisk_loader.rs");
+
+/// Owns the strings a [ZygiskLoaderContext] points to.
+pub(crate) struct EntryContext {
+    process_name: CString,
+    package_name: CString,
+    app_data_dir: CString,
+    instruction_set: CString,
+    uid: u32,
+    /// Arguments for the context; empty unless delivered there.
+    args: Vec<(CString, CString)>,
+    /// Environment variables to set; empty unless delivered there.
+    env: Vec<(String, String)>,
+}
+
+/// Prefix of the environment variables carrying per-target arguments.
+pub(crate) const ARG_ENV_PREFIX: &str = "ZYGISK_LOADER_ARG_";
+
+/// `ZYGISK_LOADER_ARG_` followed by `key` upper-cased, with anything but ASCII letters and
+/// digits replaced by `_`: `log.tag` becomes `ZYGISK_LOADER_ARG_LOG_TAG`.
+pub(crate) fn arg_env_name(key: &str) -> String {
+    let key: String = key
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{}{}", ARG_ENV_PREFIX, key)
+}
+
+/// Converts `value` for C, cutting it at the first NUL byte.
+fn c_string(value: &str) -> CString {
+    CString::new(value.split('\0').next().unwrap_or_default()).unwrap()
+}
+
+impl EntryContext {
+    pub(crate) fn new(
+        process: &ProcessInfo,
+        target: &TargetConfig,
+        data_dir: &str,
+    ) -> EntryContext {
+        EntryContext {
+            process_name: c_string(&process.name),
+            package_name: c_string(&target.package),
+            app_data_dir: c_string(data_dir),
+            instruction_set: c_string(&process.instruction_set),
+            uid: process.uid,
+            args: target
+                .args
+                .iter()
+                .filter(|_| target.arg_delivery.context)
+                .map(|(key, value)| (c_string(key), c_string(value)))
+                .collect(),
+            env: target
+                .args
+                .iter()
+                .filter(|_| target.arg_delivery.env)
+                .map(|(key, value)| (arg_env_name(key), c_string(value).into_string().unwrap()))
+                .collect(),
+        }
+    }
+
+    /// Sets the environment variables of the `env` delivery mode. Call before loading payloads,
+    /// while the process is still single-threaded.
+    pub(crate) fn export_env(&self) {
+        for (name, value) in &self.env {
+            std::env::set_var(name, value);
+        }
+        if !self.env.is_empty() {
+            debug!("Exported {} arguments to the environment", self.env.len());
+        }
+    }
+
+    /// Calls the entry point of `library` when it exports one. Fails when it returns non-zero.
+    pub(crate) fn start(&self, library: &LoadedLibrary) -> Result<(), String> {
+        let Some(symbol) = library.symbol(ZYGISK_LOADER_ENTRY) else {
+            debug!("No {} exported", ZYGISK_LOADER_ENTRY.to_string_lossy());
+            return Ok(());
+        };
+        let loader_version = c_string(env!("CARGO_PKG_VERSION"));
+        let args: Vec<ZygiskLoaderArg> = self
+            .args
+            .iter()
+            .map(|(key, value)| ZygiskLoaderArg {
+                key: key.as_ptr(),
+                value: value.as_ptr(),
+            })
+            .collect();
+        let context = ZygiskLoaderContext {
+            version: ZYGISK_LOADER_CONTEXT_VERSION,
+            size: std::mem::size_of::<ZygiskLoaderContext>() as u32,
+            loader_version: loader_version.as_ptr(),
+            process_name: self.process_name.as_ptr(),
+            package_name: self.package_name.as_ptr(),
+            app_data_dir: self.app_data_dir.as_ptr(),
+            instruction_set: self.instruction_set.as_ptr(),
+            uid: self.uid,
+            java_vm: crate::JAVA_VM
+                .get()
+                .map_or(std::ptr::null_mut(), |vm| vm.get_java_vm_pointer().cast()),
+            args: args.as_ptr(),
+            arg_count: args.len(),
+        };
+        let status: c_int = unsafe {
+            let entry: ZygiskLoaderEntry = std::mem::transmute(symbol);
+            entry(&context)
+        };
+        match status {
+            0 => Ok(()),
+            status => Err(format!(
+                "{} returned {}",
+                ZYGISK_LOADER_ENTRY.to_string_lossy(),
+                status
+            )),
+        }
+    }
+}
+
+type JniOnLoad = unsafe extern "C" fn(*mut jni::sys::JavaVM, *mut c_void) -> jint;
+
+/// Calls the `JNI_OnLoad` of `library` when it defines one itself: one reached through its
+/// dependencies belongs to another library and is never called. Like ART, only accepts JNI 1.2,
+/// 1.4 and 1.6 as the result. Runs in `post_app_specialize`, where `FindClass` only sees the
+/// boot classpath.
+pub(crate) fn jni_on_load(library: &LoadedLibrary, vm: Option<&JavaVM>) -> Result<(), String> {
+    let Some(symbol) = library.symbol(c"JNI_OnLoad") else {
+        return Ok(());
+    };
+    let vm = vm.ok_or("JNI_OnLoad exported, but no JavaVM is available")?;
+    let version = unsafe {
+        let on_load: JniOnLoad = std::mem::transmute(symbol);
+        on_load(vm.get_java_vm_pointer(), std::ptr::null_mut())
+    };
+    match version {
+        JNI_VERSION_1_2 | JNI_VERSION_1_4 | JNI_VERSION_1_6 => {
+            debug!("JNI_OnLoad requested JNI {}", jni_version_name(version));
+            Ok(())
+        }
+        JNI_ERR => Err("JNI_OnLoad returned JNI_ERR".to_string()),
+        version => Err(format!(
+            "JNI_OnLoad returned unsupported JNI version {} ({:#x})",
+            jni_version_name(version),
+            version
+        )),
+    }
+}
+
+/// `0x00010006` as `1.6`, `0x00150000` as `21.0`.
+fn jni_version_name(version: jint) -> String {
+    format!("{}.{}", version >> 16, version & 0xffff)
+}
+
+#[cfg(all(test, target_os = "linux", target_arch = "x86_64"))]
+mod test {
+    use super::*;
+    use std::ffi::{c_char, CStr};
+
+    use crate::config::LoaderConfig;
+    use crate::inject::{self, InjectStrategy};
+    use crate::reflective::test::sample_payload;
+
+    fn process() -> ProcessInfo {
+        ProcessInfo {
+            name: "com.example.app:remote".to_string(),
+            uid: 10123,
+            instruction_set: "x86_64".to_string(),
+            ..ProcessInfo::default()
+        }
+    }
+
+    #[test]
+    fn passes_context_to_entry_point() {
+        let config =
+            LoaderConfig::parse("[com.example.app]\narg.tag = Hook\narg.mode = \"a b\"\n").unwrap();
+        let context = EntryContext::new(&process(), &config.targets[0], "/data/user/0/x");
+        for strategy in [InjectStrategy::ProcFd, InjectStrategy::Reflective] {
+            let (_, library) =
+                inject::inject(&[strategy], "/nonexistent", "sample", sample_payload()).unwrap();
+            context.start(&library).unwrap();
+            let summary = library.symbol(c"sample_entry_summary").unwrap();
+            let summary: extern "C" fn() -> *const c_char = unsafe { std::mem::transmute(summary) };
+            assert_eq!(
+                unsafe { CStr::from_ptr(summary()) },
+                c"v1 com.example.app:remote com.example.app /data/user/0/x x86_64 10123 tag=Hook mode=a b",
+                "{}",
+                strategy
+            );
+        }
+    }
+
+    #[test]
+    fn calls_jni_on_load_with_the_java_vm() {
+        let fake_vm = 0x5a5a0 as *mut jni::sys::JavaVM;
+        let vm = unsafe { JavaVM::from_raw(fake_vm) }.unwrap();
+        let (_, library) = inject::inject(
+            &[InjectStrategy::Reflective],
+            "/nonexistent",
+            "sample",
+            sample_payload(),
+        )
+        .unwrap();
+        let set_version: extern "C" fn(jint) =
+            unsafe { std::mem::transmute(library.symbol(c"sample_set_jni_version").unwrap()) };
+        let seen_vm: extern "C" fn() -> *mut jni::sys::JavaVM =
+            unsafe { std::mem::transmute(library.symbol(c"sample_jni_vm").unwrap()) };
+
+        set_version(JNI_VERSION_1_6);
+        jni_on_load(&library, Some(&vm)).unwrap();
+        assert_eq!(seen_vm(), fake_vm);
+
+        set_version(jni::sys::JNI_VERSION_1_8);
+        assert_eq!(
+            jni_on_load(&library, Some(&vm)).unwrap_err(),
+            "JNI_OnLoad returned unsupported JNI version 1.8 (0x10008)"
+        );
+        set_version(JNI_ERR);
+        assert_eq!(
+            jni_on_load(&library, Some(&vm)).unwrap_err(),
+            "JNI_OnLoad returned JNI_ERR"
+        );
+        assert!(jni_on_load(&library, None).is_err());
+    }
+
+    #[test]
+    fn skips_jni_on_load_of_dependencies() {
+        let dir = std::env::temp_dir().join(format!("zygiskloader-jni-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("cache")).unwrap();
+        let (_, library) = inject::inject(
+            &[InjectStrategy::CacheFile],
+            dir.to_str().unwrap(),
+            "sample",
+            sample_payload(),
+        )
+        .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            jni_on_load(&library, None).unwrap_err(),
+            "JNI_OnLoad exported, but no JavaVM is available"
+        );
+
+        // The same handle seen from a payload that only depends on the sample.
+        let LoadedLibrary::Linker { handle, .. } = library else {
+            unreachable!();
+        };
+        let dependent = LoadedLibrary::Linker {
+            handle,
+            base: Some(0x1000),
+        };
+        assert!(dependent.symbol(c"JNI_OnLoad").is_none());
+        jni_on_load(&dependent, None).unwrap();
+    }
+
+    #[test]
+    fn delivers_args_through_the_environment() {
+        let config = LoaderConfig::parse(
+            "[com.example.app]\narg.log.tag = Hook\narg.mode = \"a b\"\narg_delivery = env\n",
+        )
+        .unwrap();
+        let context = EntryContext::new(&process(), &config.targets[0], "");
+        context.export_env();
+        assert_eq!(std::env::var("ZYGISK_LOADER_ARG_LOG_TAG").unwrap(), "Hook");
+        assert_eq!(std::env::var("ZYGISK_LOADER_ARG_MODE").unwrap(), "a b");
+
+        // Not in the context unless asked for.
+        let (_, library) = inject::inject(
+            &[InjectStrategy::Reflective],
+            "/nonexistent",
+            "sample",
+            sample_payload(),
+        )
+        .unwrap();
+        context.start(&library).unwrap();
+        let summary = library.symbol(c"sample_entry_summary").unwrap();
+        let summary: extern "C" fn() -> *const c_char = unsafe { std::mem::transmute(summary) };
+        assert_eq!(
+            unsafe { CStr::from_ptr(summary()) },
+            c"v1 com.example.app:remote com.example.app  x86_64 10123"
+        );
+    }
+
+    #[test]
+    fn reports_entry_point_failures() {
+        let config = LoaderConfig::parse("[com.example.app]\narg.exit_code = 3\n").unwrap();
+        let context = EntryContext::new(&process(), &config.targets[0], "");
+        let (_, library) = inject::inject(
+            &[InjectStrategy::Reflective],
+            "/nonexistent",
+            "sample",
+            sample_payload(),
+        )
+        .unwrap();
+        assert_eq!(
+            context.start(&library).unwrap_err(),
+            "zygisk_loader_main returned 3"
+        );
+    }
+}
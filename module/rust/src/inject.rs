@@ -0,0 +1,585 @@
+//! Injection strategies: how a payload image, in RAM or in a file opened before
+//! specialization, becomes a loaded library.
+//!
+//! Each target has an ordered chain of strategies. They are tried in turn until one loads the
+//! payload, so devices where the stealthier methods fail still fall back to a working one.
+
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+use crate::reflective::{self, MappedImage};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum InjectStrategy {
+    /// Write to a memfd and load it with `android_dlopen_ext(ANDROID_DLEXT_USE_LIBRARY_FD)`.
+    /// Nothing touches the filesystem. A file image is loaded from its own descriptor.
+    Memfd,
+    /// Write to a memfd and `dlopen` its `/proc/self/fd/<n>` path. A file image is opened
+    /// through its own descriptor.
+    ProcFd,
+    /// Write to `<data dir>/cache`, `dlopen` it and unlink it right away. A file image is copied
+    /// in the kernel, without passing through RAM.
+    CacheFile,
+    /// Map the image from RAM with the module's own loader. Invisible to the linker, so it is
+    /// never part of the default chain.
+    Reflective,
+}
+
+impl InjectStrategy {
+    pub(crate) const DEFAULT_CHAIN: [InjectStrategy; 3] = [
+        InjectStrategy::Memfd,
+        InjectStrategy::ProcFd,
+        InjectStrategy::CacheFile,
+    ];
+
+    pub(crate) fn parse(name: &str) -> Option<InjectStrategy> {
+        match name {
+            "memfd" => Some(InjectStrategy::Memfd),
+            "proc-fd" => Some(InjectStrategy::ProcFd),
+            "cache-file" => Some(InjectStrategy::CacheFile),
+            "reflective" => Some(InjectStrategy::Reflective),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for InjectStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            InjectStrategy::Memfd => "memfd",
+            InjectStrategy::ProcFd => "proc-fd",
+            InjectStrategy::CacheFile => "cache-file",
+            InjectStrategy::Reflective => "reflective",
+        })
+    }
+}
+
+/// A payload loaded by one of the strategies.
+pub(crate) enum LoadedLibrary {
+    /// A handle from the system linker, with the load base of the payload's own object when one
+    /// of its exports could be traced back to it.
+    Linker {
+        handle: *mut c_void,
+        base: Option<usize>,
+    },
+    Reflective(MappedImage),
+}
+
+impl LoadedLibrary {
+    fn linked(handle: *mut c_void, image: Image) -> LoadedLibrary {
+        let base = own_base(handle, image);
+        if base.is_none() {
+            warn!("No export of the payload resolves to its own object");
+        }
+        LoadedLibrary::Linker { handle, base }
+    }
+
+    /// Address of the symbol `name` exported by the payload itself. `dlsym` on a handle also
+    /// searches the library's dependencies, so a definition from anywhere else is ignored.
+    pub(crate) fn symbol(&self, name: &CStr) -> Option<*mut c_void> {
+        match self {
+            LoadedLibrary::Linker { handle, base } => {
+                let address = unsafe { libc::dlsym(*handle, name.as_ptr()) };
+                (!address.is_null() && object_base(address) == *base).then_some(address)
+            }
+            LoadedLibrary::Reflective(image) => image.symbol(name),
+        }
+    }
+}
+
+/// Load base of the object containing `address`, as reported by `dladdr`.
+fn object_base(address: *mut c_void) -> Option<usize> {
+    let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+    let found = unsafe { libc::dladdr(address, &mut info) } != 0;
+    (found && !info.dli_fbase.is_null()).then_some(info.dli_fbase as usize)
+}
+
+/// Load base of the object `handle` was opened from. One of the image's own exports is looked
+/// up: `dlsym` searches the object itself before its dependencies, so it resolves there.
+fn own_base(handle: *mut c_void, image: Image) -> Option<usize> {
+    let map;
+    let data: &[u8] = match image {
+        Image::Bytes(data) => data,
+        Image::File(file) => {
+            map = FileMap::new(file).ok()?;
+            &map
+        }
+    };
+    let name = CString::new(crate::elf::first_export(data).ok()??).ok()?;
+    let address = unsafe { libc::dlsym(handle, name.as_ptr()) };
+    if address.is_null() {
+        return None;
+    }
+    object_base(address)
+}
+
+impl fmt::Display for LoadedLibrary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadedLibrary::Linker { handle, .. } => write!(f, "Handle: {:p}", handle),
+            LoadedLibrary::Reflective(image) => write!(f, "Mapped at {}", image),
+        }
+    }
+}
+
+/// A loadable payload image.
+#[derive(Clone, Copy)]
+pub(crate) enum Image<'a> {
+    Bytes(&'a [u8]),
+    /// A file holding the image as is, read from its start.
+    File(&'a File),
+}
+
+impl<'a> From<&'a [u8]> for Image<'a> {
+    fn from(data: &'a [u8]) -> Image<'a> {
+        Image::Bytes(data)
+    }
+}
+
+impl<'a> From<&'a Vec<u8>> for Image<'a> {
+    fn from(data: &'a Vec<u8>) -> Image<'a> {
+        Image::Bytes(data)
+    }
+}
+
+impl<'a> From<&'a File> for Image<'a> {
+    fn from(file: &'a File) -> Image<'a> {
+        Image::File(file)
+    }
+}
+
+/// A read-only, private mapping of a whole file.
+pub(crate) struct FileMap {
+    address: *mut c_void,
+    len: usize,
+}
+
+impl FileMap {
+    pub(crate) fn new(file: &File) -> io::Result<FileMap> {
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            // mmap rejects empty mappings.
+            return Ok(FileMap {
+                address: std::ptr::null_mut(),
+                len,
+            });
+        }
+        let address = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if address == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(FileMap { address, len })
+    }
+}
+
+impl Deref for FileMap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.address.cast(), self.len) }
+    }
+}
+
+impl Drop for FileMap {
+    fn drop(&mut self) {
+        if self.len != 0 {
+            unsafe { libc::munmap(self.address, self.len) };
+        }
+    }
+}
+
+/// Loads `image` with the first strategy of `chain` that works. `source` is only used for
+/// logging. Returns the strategy that succeeded with the loaded library.
+pub(crate) fn inject<'a>(
+    chain: &[InjectStrategy],
+    data_dir: &str,
+    source: &str,
+    image: impl Into<Image<'a>>,
+) -> Result<(InjectStrategy, LoadedLibrary), String> {
+    let image = image.into();
+    let linked = |handle| LoadedLibrary::linked(handle, image);
+    let mut failures = Vec::with_capacity(chain.len());
+    for &strategy in chain {
+        let result = match strategy {
+            InjectStrategy::Memfd => load_with_dlext(image).map(linked),
+            InjectStrategy::ProcFd => load_from_proc_fd(image).map(linked),
+            InjectStrategy::CacheFile => load_from_cache_file(data_dir, source, image).map(linked),
+            InjectStrategy::Reflective => load_reflective(image).map(LoadedLibrary::Reflective),
+        };
+        match result {
+            Ok(library) => return Ok((strategy, library)),
+            Err(e) => {
+                warn!("Strategy {} failed for {}: {}", strategy, source, e);
+                failures.push(format!("{}: {}", strategy, e));
+            }
+        }
+    }
+    Err(format!("no strategy succeeded ({})", failures.join("; ")))
+}
+
+/// Seals that make a memfd immutable: its contents can no longer change, nor its seals.
+const MEMFD_SEALS: c_int =
+    libc::F_SEAL_SEAL | libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE;
+
+/// Creates an anonymous, close-on-exec memory file with an inconspicuous name.
+fn create_memfd(flags: libc::c_uint) -> io::Result<File> {
+    let name = CString::new(format!(".res_{}.so", crate::rand_int())).unwrap();
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC | flags) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Creates an anonymous, close-on-exec memory file holding `data`.
+fn memfd_with(data: &[u8]) -> Result<File, String> {
+    let mut file = create_memfd(0).map_err(|e| format!("memfd_create: {}", e))?;
+    file.write_all(data)
+        .map_err(|e| format!("failed to fill memfd: {}", e))?;
+    Ok(file)
+}
+
+/// Copies `file`, from its current offset, into a memfd that can no longer be written, grown
+/// or shrunk. Returned positioned at the start.
+pub(crate) fn sealed_copy(mut file: &File) -> io::Result<File> {
+    let mut memfd = create_memfd(libc::MFD_ALLOW_SEALING)?;
+    // Copied in the kernel where possible.
+    io::copy(&mut file, &mut memfd)?;
+    memfd.seek(SeekFrom::Start(0))?;
+    if unsafe { libc::fcntl(memfd.as_raw_fd(), libc::F_ADD_SEALS, MEMFD_SEALS) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(memfd)
+}
+
+/// Whether `file` is a memfd whose contents can no longer change.
+pub(crate) fn is_sealed(file: &File) -> bool {
+    let seals = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GET_SEALS) };
+    seals >= 0 && seals & MEMFD_SEALS == MEMFD_SEALS
+}
+
+/// A descriptor of `image`: a duplicate of its file, or a new memfd holding its bytes.
+fn image_file(image: Image) -> Result<File, String> {
+    match image {
+        Image::Bytes(data) => memfd_with(data),
+        Image::File(file) => file.try_clone().map_err(|e| format!("dup: {}", e)),
+    }
+}
+
+const ANDROID_DLEXT_USE_LIBRARY_FD: u64 = 0x10;
+
+/// `android_dlextinfo` from `<android/dlext.h>`.
+#[repr(C)]
+struct AndroidDlextInfo {
+    flags: u64,
+    reserved_addr: *mut c_void,
+    reserved_size: usize,
+    relro_fd: c_int,
+    library_fd: c_int,
+    library_fd_offset: i64,
+    library_namespace: *mut c_void,
+}
+
+type AndroidDlopenExt =
+    unsafe extern "C" fn(*const c_char, c_int, *const AndroidDlextInfo) -> *mut c_void;
+
+fn load_with_dlext(image: Image) -> Result<*mut c_void, String> {
+    // Resolved at run time: only bionic has it, and hosts without it just fall through.
+    let symbol = unsafe { libc::dlsym(libc::RTLD_DEFAULT, c"android_dlopen_ext".as_ptr()) };
+    if symbol.is_null() {
+        return Err("android_dlopen_ext is not available".to_string());
+    }
+    let android_dlopen_ext: AndroidDlopenExt = unsafe { std::mem::transmute(symbol) };
+
+    let file = image_file(image)?;
+    let info = AndroidDlextInfo {
+        flags: ANDROID_DLEXT_USE_LIBRARY_FD,
+        reserved_addr: std::ptr::null_mut(),
+        reserved_size: 0,
+        relro_fd: -1,
+        library_fd: file.as_raw_fd(),
+        library_fd_offset: 0,
+        library_namespace: std::ptr::null_mut(),
+    };
+    let name = CString::new(format!(".res_{}.so", crate::rand_int())).unwrap();
+    let handle = unsafe { android_dlopen_ext(name.as_ptr(), libc::RTLD_NOW, &info) };
+    if handle.is_null() {
+        Err(crate::dlerror_string())
+    } else {
+        Ok(handle)
+    }
+}
+
+fn load_from_proc_fd(image: Image) -> Result<*mut c_void, String> {
+    let file = image_file(image)?;
+    // The mapping keeps the file alive once `file` is closed.
+    crate::dlopen_payload(&format!("/proc/self/fd/{}", file.as_raw_fd()))
+}
+
+fn load_from_cache_file(data_dir: &str, source: &str, image: Image) -> Result<*mut c_void, String> {
+    // Generate a random filename to avoid collisions and look like a cache file
+    let file_name = format!("{}/cache/.res_{}.so", data_dir, crate::rand_int());
+
+    info!("Attempting injection of {} to: {}", source, file_name);
+
+    let result = match image {
+        Image::Bytes(data) => crate::write_file(&file_name, data),
+        Image::File(file) => copy_to_path(file, &file_name),
+    }
+    .map_err(|e| format!("failed to write payload: {}", e))
+    .and_then(|()| crate::dlopen_payload(&file_name));
+
+    // Immediately unlink (delete) the file from disk, partial writes included
+    // The kernel keeps the file in memory as long as it's mapped,
+    // but the file entry is removed from the filesystem.
+    let _ = std::fs::remove_file(&file_name);
+
+    result
+}
+
+/// Copies all of `source` to a new file at `path` inside the kernel: `copy_file_range`, or
+/// `sendfile` where it cannot cross filesystems (before Linux 5.3) or is missing.
+fn copy_to_path(source: &File, path: &str) -> io::Result<()> {
+    let target = File::create(path)?;
+    let len = source.metadata()?.len();
+    let mut offset: libc::off64_t = 0;
+    let mut use_sendfile = false;
+    while (offset as u64) < len {
+        let remaining = (len - offset as u64) as usize;
+        let copied = if use_sendfile {
+            unsafe {
+                libc::sendfile64(
+                    target.as_raw_fd(),
+                    source.as_raw_fd(),
+                    &mut offset,
+                    remaining,
+                )
+            }
+        } else {
+            // Through syscall(): bionic only wraps it from API 34.
+            unsafe {
+                libc::syscall(
+                    libc::SYS_copy_file_range,
+                    source.as_raw_fd(),
+                    &mut offset,
+                    target.as_raw_fd(),
+                    std::ptr::null_mut::<libc::off64_t>(),
+                    remaining,
+                    0,
+                ) as isize
+            }
+        };
+        if copied < 0 {
+            let error = io::Error::last_os_error();
+            match error.raw_os_error() {
+                Some(libc::EXDEV | libc::ENOSYS | libc::EOPNOTSUPP | libc::EINVAL)
+                    if !use_sendfile =>
+                {
+                    use_sendfile = true;
+                    continue;
+                }
+                Some(libc::EINTR) => continue,
+                _ => return Err(error),
+            }
+        }
+        if copied == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+    target.sync_all()
+}
+
+fn load_reflective(image: Image) -> Result<MappedImage, String> {
+    let map;
+    let data = match image {
+        Image::Bytes(data) => data,
+        Image::File(file) => {
+            map = FileMap::new(file).map_err(|e| format!("mmap: {}", e))?;
+            &map
+        }
+    };
+    reflective::load(data).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HOST_LIBRARIES: [&str; 2] = ["/lib/x86_64-linux-gnu/libz.so.1", "/usr/lib/libz.so.1"];
+
+    /// A small shared library from the host, to exercise the real loaders.
+    fn host_library() -> Option<Vec<u8>> {
+        HOST_LIBRARIES
+            .iter()
+            .find_map(|path| std::fs::read(path).ok())
+    }
+
+    #[test]
+    fn parses_strategy_names() {
+        for strategy in InjectStrategy::DEFAULT_CHAIN
+            .into_iter()
+            .chain([InjectStrategy::Reflective])
+        {
+            assert_eq!(InjectStrategy::parse(&strategy.to_string()), Some(strategy));
+        }
+        assert_eq!(InjectStrategy::parse("memfd-fd"), None);
+    }
+
+    #[test]
+    fn falls_back_along_the_chain() {
+        let Some(library) = host_library() else {
+            return;
+        };
+        // glibc has no android_dlopen_ext, so the memfd strategy must hand over to proc-fd.
+        let (strategy, loaded) = inject(
+            &InjectStrategy::DEFAULT_CHAIN,
+            "/nonexistent",
+            "libz.so.1",
+            &library,
+        )
+        .unwrap();
+        assert_eq!(strategy, InjectStrategy::ProcFd);
+        assert!(matches!(loaded, LoadedLibrary::Linker { handle, .. } if !handle.is_null()));
+
+        let err = inject(
+            &[InjectStrategy::Memfd],
+            "/nonexistent",
+            "libz.so.1",
+            &library,
+        )
+        .err()
+        .unwrap();
+        assert!(err.contains("memfd: android_dlopen_ext"), "{}", err);
+    }
+
+    #[test]
+    fn resolves_only_symbols_of_the_payload_itself() {
+        let Some(library) = host_library() else {
+            return;
+        };
+        // glibc hands back an earlier library for a reused /proc/self/fd path, so load from a
+        // uniquely named cache file.
+        let dir = std::env::temp_dir().join(format!("zygiskloader-symbol-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("cache")).unwrap();
+        let (_, loaded) = inject(
+            &[InjectStrategy::CacheFile],
+            dir.to_str().unwrap(),
+            "libz.so.1",
+            &library,
+        )
+        .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(loaded.symbol(c"zlibVersion").is_some());
+        // libz depends on libc, so dlsym on its handle finds malloc there.
+        let LoadedLibrary::Linker { handle, .. } = &loaded else {
+            unreachable!();
+        };
+        assert!(!unsafe { libc::dlsym(*handle, c"malloc".as_ptr()) }.is_null());
+        assert_eq!(loaded.symbol(c"malloc"), None);
+    }
+
+    #[test]
+    fn cache_file_is_unlinked_after_loading() {
+        let Some(library) = host_library() else {
+            return;
+        };
+        let dir = std::env::temp_dir().join(format!("zygiskloader-inject-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("cache")).unwrap();
+
+        let (strategy, loaded) = inject(
+            &[InjectStrategy::CacheFile],
+            dir.to_str().unwrap(),
+            "libz.so.1",
+            &library,
+        )
+        .unwrap();
+        assert_eq!(strategy, InjectStrategy::CacheFile);
+        assert!(matches!(
+            loaded,
+            LoadedLibrary::Linker { handle, base: Some(_) } if !handle.is_null()
+        ));
+        assert_eq!(std::fs::read_dir(dir.join("cache")).unwrap().count(), 0);
+
+        // A failed copy leaves nothing behind either.
+        let source = dir.join("source.so");
+        std::fs::write(&source, &library).unwrap();
+        let unreadable = File::options().write(true).open(&source).unwrap();
+        let err = inject(
+            &[InjectStrategy::CacheFile],
+            dir.to_str().unwrap(),
+            "libz.so.1",
+            &unreadable,
+        )
+        .err()
+        .unwrap();
+        assert!(err.contains("failed to write payload"), "{}", err);
+        assert_eq!(std::fs::read_dir(dir.join("cache")).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn loads_from_an_open_file() {
+        let Some(file) = HOST_LIBRARIES.iter().find_map(|path| File::open(path).ok()) else {
+            return;
+        };
+        let dir =
+            std::env::temp_dir().join(format!("zygiskloader-inject-fd-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("cache")).unwrap();
+
+        for strategy in [InjectStrategy::ProcFd, InjectStrategy::CacheFile] {
+            let (_, loaded) =
+                inject(&[strategy], dir.to_str().unwrap(), "libz.so.1", &file).unwrap();
+            assert!(matches!(loaded, LoadedLibrary::Linker { handle, .. } if !handle.is_null()));
+        }
+        assert_eq!(std::fs::read_dir(dir.join("cache")).unwrap().count(), 0);
+
+        // The copy is byte for byte, and leaves the file offset at the start.
+        let copy = dir.join("copy.so");
+        copy_to_path(&file, copy.to_str().unwrap()).unwrap();
+        let mut rest = Vec::new();
+        std::io::Read::read_to_end(&mut &file, &mut rest).unwrap();
+        assert_eq!(std::fs::read(&copy).unwrap(), rest);
+        assert_eq!(*FileMap::new(&file).unwrap(), rest);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn seals_copies() {
+        let Some(path) = HOST_LIBRARIES.iter().find(|path| File::open(path).is_ok()) else {
+            return;
+        };
+        let file = File::open(path).unwrap();
+        assert!(!is_sealed(&file));
+        let mut memfd = sealed_copy(&file).unwrap();
+        assert!(is_sealed(&memfd));
+        assert!(memfd.write_all(b"x").is_err());
+        assert!(memfd.set_len(0).is_err());
+        let mut copy = Vec::new();
+        std::io::Read::read_to_end(&mut memfd, &mut copy).unwrap();
+        assert_eq!(copy, std::fs::read(path).unwrap());
+    }
+
+    #[test]
+    fn maps_empty_files() {
+        let file = File::open("/dev/null").unwrap();
+        assert!(FileMap::new(&file).unwrap().is_empty());
+    }
+}
@@ -1,8 +1,25 @@
 mod api;
 mod binding;
+mod companion;
+mod compress;
+mod config;
+// Encryption is only used by the `zygisk-loader-sign` tool.
+#[cfg_attr(feature = "host-tools", allow(dead_code))]
+mod crypt;
+mod elf;
+mod entry;
+mod inject;
 #[doc(hidden)]
 pub mod macros;
+mod matcher;
 mod module;
+mod packages;
+mod payload;
+mod process;
+mod reflective;
+// The signing half is only used by the `zygisk-loader-sign` tool.
+#[cfg_attr(feature = "host-tools", allow(dead_code))]
+mod signature;
 
 #[macro_use]
 extern crate log;
@@ -14,29 +31,50 @@ use android_logger::Config;
 #[cfg(target_os = "android")]
 use log::LevelFilter;
 
+use std::cell::OnceCell;
 use std::ffi::{CStr, CString};
 use std::fs::File;
-use std::io::{BufRead, BufReader, Read, Write};
-use std::sync::OnceLock;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Mutex, OnceLock};
+
+use jni::objects::JString;
+use jni::{JNIEnv, JavaVM};
+use zeroize::Zeroizing;
 
 pub use api::ZygiskApi;
 pub use binding::{AppSpecializeArgs, ServerSpecializeArgs, StateFlags, ZygiskOption, API_VERSION};
-use jni::{JNIEnv, JavaVM};
+use compress::{Compression, MAX_DECOMPRESSED_SIZE};
+use config::{ConfigError, InjectTiming, LoaderConfig, TargetConfig};
+use crypt::PayloadKey;
+use entry::{jni_on_load, EntryContext};
+use inject::{FileMap, InjectStrategy};
 pub use module::ZygiskModule;
+use packages::{PackageList, PACKAGES_LIST_PATH};
+use payload::{
+    load_in_order, open_payload, payload_paths, PayloadBuffer, PayloadImage, StoredFormat,
+};
+use process::{ProcessInfo, ProcessKind};
+use signature::{signature_path, TrustStore};
 
 // Config & Source Payload path
-const CONFIG_PATH: &str = "/data/adb/modules/zygisk-loader/config/target";
-const SOURCE_PAYLOAD_PATH: &str = "/data/adb/modules/zygisk-loader/config/payload.so";
+const MODULE_DIR: &str = "/data/adb/modules/zygisk-loader";
+const CONFIG_DIR: &str = "/data/adb/modules/zygisk-loader/config";
+const CONFIG_PATH: &str = "/data/adb/modules/zygisk-loader/config/loader.conf";
+const LEGACY_CONFIG_PATH: &str = "/data/adb/modules/zygisk-loader/config/target";
+const TRUSTED_KEYS_PATH: &str = "/data/adb/modules/zygisk-loader/trusted_keys";
+const PAYLOAD_KEY_PATH: &str = "/data/adb/modules/zygisk-loader/payload.key";
 
 static MODULE: ZygiskLoaderModule = ZygiskLoaderModule {};
 crate::zygisk_module!(&MODULE);
+#[cfg(not(test))]
+crate::zygisk_companion!(companion::handle_request);
 
 struct ZygiskLoaderModule {}
 
 static JAVA_VM: OnceLock<JavaVM> = OnceLock::new();
-static TARGET_CONFIG: OnceLock<String> = OnceLock::new();
-static PAYLOAD_BUFFER: OnceLock<Vec<u8>> = OnceLock::new();
-static TARGET_APP_DETECTED: OnceLock<bool> = OnceLock::new();
+static TARGET_CONFIG: OnceLock<LoaderConfig> = OnceLock::new();
+static PENDING_INJECTION: Mutex<Option<PendingInjection>> = Mutex::new(None);
 
 fn rand_int() -> u32 {
     // Simple pseudo-random for filename obfuscation using time
@@ -61,6 +99,152 @@ fn read_file_to_memory(path: &str) -> std::io::Result<Vec<u8>> {
     Ok(buffer)
 }
 
+impl ZygiskLoaderModule {
+    /// Matches the process against the config and readies the payloads of its target.
+    fn prepare(&self, api: &ZygiskApi, args: &mut AppSpecializeArgs) -> Residency {
+        // 1. Read Config (As Root/Zygote)
+        // Zygisk loads the module after the fork, so there is no zygote copy to inherit.
+        match read_target_config(api) {
+            Ok(config) => {
+                let _ = TARGET_CONFIG.set(config);
+            },
+            Err(ConfigError::Io(e)) if e.kind() == ErrorKind::NotFound => {
+                debug!("No config found at {} or {}", CONFIG_PATH, LEGACY_CONFIG_PATH);
+            },
+            Err(e) => {
+                error!("Failed to load config: {}", e);
+            }
+        }
+        let config = match TARGET_CONFIG.get() {
+            Some(config) => config,
+            None => return Residency::Unload,
+        };
+        if !config.targets.iter().any(|t| t.enabled) {
+            return Residency::Unload;
+        }
+
+        let uid = *args.uid as u32;
+        let is_child_zygote = args.is_child_zygote.is_some_and(|b| *b != 0);
+        let mut current_process = ProcessInfo {
+            name: get_process_name_from_args_safe(args),
+            uid,
+            kind: ProcessKind::classify(uid, is_child_zygote),
+            se_info: get_se_info_from_args(args),
+            packages: Vec::new(),
+            is_top_app: args.is_top_app.is_some_and(|b| *b != 0),
+            is_first_start: false,
+            on_denylist: false,
+            instruction_set: get_instruction_set_from_args(args),
+        };
+
+        let flags = api.get_flags();
+        current_process.is_first_start = flags.contains(StateFlags::PROCESS_IS_FIRST_STARTED);
+        current_process.on_denylist = flags.contains(StateFlags::PROCESS_ON_DENYLIST);
+
+        if config.needs_packages() {
+            // Only readable as root, so this has to happen before specialization.
+            match PackageList::load(PACKAGES_LIST_PATH) {
+                Ok(list) => {
+                    current_process.packages = list.packages_for_uid(uid).cloned().collect();
+                },
+                Err(e) => error!("Failed to read {}: {}", PACKAGES_LIST_PATH, e),
+            }
+        }
+
+        let target = config.targets.iter().find(|t| t.matches(&current_process));
+
+        if let Some(target) = target {
+            info!(
+                "Target Detected: {} uid {} kind {} isa {} (target {}, {})",
+                current_process.name,
+                current_process.uid,
+                current_process.kind,
+                current_process.instruction_set,
+                target.package,
+                target.matcher
+            );
+
+            // Applied during specialization; payloads come from the buffers or fds opened here.
+            if target.force_denylist_unmount {
+                api.set_option(ZygiskOption::ForceDenylistUnmount);
+            }
+            info!(
+                "Root unmount {}, process {} the denylist",
+                if target.force_denylist_unmount { "forced" } else { "not requested" },
+                if current_process.on_denylist { "on" } else { "not on" }
+            );
+
+            // Signatures are enforced as soon as a trust store exists; a broken one blocks everything.
+            let trust_store = match TrustStore::from_file(read_module_file(api, TRUSTED_KEYS_PATH)) {
+                Ok(store) => store,
+                Err(e) => {
+                    error!("Refusing to inject, {}: {}", TRUSTED_KEYS_PATH, e);
+                    return Residency::Unload;
+                }
+            };
+            if let Some(store) = &trust_store {
+                info!("Payload signatures required ({} trusted keys)", store.len());
+            }
+            let context = PayloadContext {
+                api,
+                instruction_set: &current_process.instruction_set,
+                trust_store,
+                payload_key: OnceCell::new(),
+            };
+            let data_dir = get_app_data_dir_from_args(args);
+            let entry = EntryContext::new(&current_process, target, &data_dir);
+
+            if target.timing == InjectTiming::PreSpecialize {
+                // Load the verified image itself: reopening the path could pick up another file.
+                entry.export_env();
+                load_in_order(&target.payloads, |payload| {
+                    let (path, file) = open_payload_file(&context, &payload.name);
+                    let (image, _) = buffer_payload(&path, file?, &context)?;
+                    let (strategy, library) =
+                        inject::inject(&target.inject, &data_dir, &path, image.image())?;
+                    entry.start(&library)?;
+                    Ok(format!("{} via {}", library, strategy))
+                });
+                return Residency::for_target(target);
+            }
+
+            if current_process.kind.is_isolated() {
+                warn!(
+                    "{} is a {} process, its data dir is usually not writable",
+                    current_process.name, current_process.kind
+                );
+            }
+
+            // 2. Open Payloads; plain files stay open across specialization, the rest go to RAM
+            let mut buffers = Vec::with_capacity(target.payloads.len());
+            for spec in &target.payloads {
+                let (path, file) = open_payload_file(&context, &spec.name);
+                let data = file
+                    .and_then(|file| buffer_payload(&path, file, &context))
+                    .and_then(|(image, stored)| {
+                        let image = keep_across_specialization(api, image)?;
+                        info!("Payload ready: {} ({}, {})", path, image, stored);
+                        Ok(image)
+                    });
+                if let Err(e) = &data {
+                    error!("Rejected payload {}: {}", path, e);
+                }
+                buffers.push(PayloadBuffer { spec: spec.clone(), path, data });
+            }
+            if let Ok(mut pending) = PENDING_INJECTION.lock() {
+                *pending = Some(PendingInjection {
+                    chain: target.inject.clone(),
+                    entry,
+                    buffers,
+                });
+            }
+            Residency::for_target(target)
+        } else {
+            Residency::Unload
+        }
+    }
+}
+
 impl ZygiskModule for ZygiskLoaderModule {
     fn on_load(&self, _api: ZygiskApi, env: &mut JNIEnv) {
         #[cfg(target_os = "android")]
@@ -75,38 +259,19 @@ impl ZygiskModule for ZygiskLoaderModule {
         info!("Zygisk-Loader Initialized");
     }
 
-    fn pre_app_specialize(&self, _api: ZygiskApi, args: &mut AppSpecializeArgs) {
-        // 1. Read Config (As Root/Zygote)
-        if let Ok(target) = read_target_config() {
-            let _ = TARGET_CONFIG.set(target);
-        }
-
-        let current_process = get_process_name_from_args_safe(args);
-        let target_package = TARGET_CONFIG.get().map(|s| s.as_str()).unwrap_or("");
-
-        if !target_package.is_empty() && current_process.contains(target_package) {
-            info!("Target Detected: {}", current_process);
-            let _ = TARGET_APP_DETECTED.set(true);
-
-            // 2. Read Payload to RAM
-            match read_file_to_memory(SOURCE_PAYLOAD_PATH) {
-                Ok(buffer) => {
-                    info!("Payload buffered to RAM: {} bytes", buffer.len());
-                    let _ = PAYLOAD_BUFFER.set(buffer);
-                },
-                Err(e) => {
-                    error!("Failed to buffer payload from {}: {}", SOURCE_PAYLOAD_PATH, e);
-                }
-            }
+    fn pre_app_specialize(&self, api: ZygiskApi, args: &mut AppSpecializeArgs) {
+        let residency = self.prepare(&api, args);
+        debug!("Loader residency: {}", residency);
+        if residency == Residency::Unload {
+            api.set_option(ZygiskOption::DlcloseModuleLibrary);
         }
     }
 
     fn post_app_specialize(&self, _api: ZygiskApi, args: &AppSpecializeArgs) {
-        if TARGET_APP_DETECTED.get() != Some(&true) {
-            return;
-        }
+        // Taken rather than borrowed: the buffers are scrubbed and the fds closed once it drops.
+        let pending = PENDING_INJECTION.lock().ok().and_then(|mut pending| pending.take());
 
-        if let Some(buffer) = PAYLOAD_BUFFER.get() {
+        if let Some(PendingInjection { chain, entry, buffers }) = pending {
             // FIX: Use app_data_dir directly instead of nice_name
             // This ensures we write to the correct folder even for isolated processes (e.g., :remote)
             let data_dir = get_app_data_dir_from_args(args);
@@ -116,71 +281,246 @@ impl ZygiskModule for ZygiskLoaderModule {
                 return;
             }
 
-            // Generate a random filename to avoid collisions and look like a cache file
-            let file_name = format!("{}/cache/.res_{}.so", data_dir, rand_int());
-            
-            info!("Attempting injection to: {}", file_name);
-
-            match write_file(&file_name, buffer) {
-                Ok(_) => {
-                    let c_path = CString::new(file_name.clone()).unwrap();
-                    unsafe {
-                        let handle = libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW);
-                        
-                        // Immediately unlink (delete) the file from disk
-                        // The kernel keeps the file in memory as long as it's mapped, 
-                        // but the file entry is removed from the filesystem.
-                        let _ = std::fs::remove_file(&file_name); 
-
-                        if handle.is_null() {
-                            let err = CStr::from_ptr(libc::dlerror()).to_string_lossy();
-                            error!("Injection failed: {}", err);
-                        } else {
-                            info!("Injection success! Handle: {:p}", handle);
-                        }
-                    }
+            entry.export_env();
+            load_in_order(&buffers, |buffer| {
+                let image = buffer.data.as_ref()?;
+                let (strategy, library) =
+                    inject::inject(&chain, &data_dir, &buffer.path, image.image())?;
+                jni_on_load(&library, JAVA_VM.get())?;
+                entry.start(&library)?;
+                Ok(format!("{} via {}", library, strategy))
+            });
+        }
+    }
+}
+
+/// Whether the loader library stays mapped once specialization is over, decided once per
+/// process in `pre_app_specialize`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Residency {
+    /// Zygisk unloads it after `post_app_specialize`. The policy for every process that is not a
+    /// target, and for targets whose payloads never call back into the loader.
+    Unload,
+    /// Stays mapped for the life of the process.
+    Keep,
+}
+
+impl Residency {
+    /// Targets keep the loader only when a payload may call into it. The one such call is
+    /// `__tls_get_addr`, which images mapped by the `reflective` strategy have bound to the
+    /// loader on x86_64; elsewhere images with thread-locals are not mapped reflectively at all.
+    fn for_target(target: &TargetConfig) -> Residency {
+        if cfg!(target_arch = "x86_64") && target.inject.contains(&InjectStrategy::Reflective) {
+            Residency::Keep
+        } else {
+            Residency::Unload
+        }
+    }
+}
+
+impl std::fmt::Display for Residency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Residency::Unload => "unload after specialization",
+            Residency::Keep => "keep loaded",
+        })
+    }
+}
+
+/// What `pre_app_specialize` leaves to `post_app_specialize` in a target process.
+struct PendingInjection {
+    chain: Vec<InjectStrategy>,
+    entry: EntryContext,
+    buffers: Vec<PayloadBuffer>,
+}
+
+/// Everything `buffer_payload` needs to turn a stored payload into a loadable image.
+struct PayloadContext<'a> {
+    api: &'a ZygiskApi<'a>,
+    instruction_set: &'a str,
+    trust_store: Option<TrustStore>,
+    payload_key: OnceCell<Result<PayloadKey, String>>,
+}
+
+impl PayloadContext<'_> {
+    /// The payload key, read on first use. When SELinux keeps the process out of the module
+    /// directory, the companion reads it instead.
+    fn payload_key(&self) -> Result<&PayloadKey, String> {
+        self.payload_key
+            .get_or_init(|| match PayloadKey::load(PAYLOAD_KEY_PATH) {
+                Ok(key) => Ok(key),
+                Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                    companion::request_payload_key(self.api)
                 },
-                Err(e) => error!("Failed to write payload: {}", e)
-            }
+                Err(e) => Err(format!("{}: {}", PAYLOAD_KEY_PATH, e)),
+            })
+            .as_ref()
+            .map_err(Clone::clone)
+    }
+}
+
+/// Opens the payload `name` for the process ABI, with the companion when this process may not
+/// open or even look for it. Returns the path picked, for logging.
+fn open_payload_file(context: &PayloadContext, name: &str) -> (String, Result<File, String>) {
+    let paths = payload_paths(CONFIG_DIR, name, context.instruction_set);
+    let (path, file) = open_payload(paths, |path| open_module_file(context.api, path));
+    (path, file.map_err(|e| format!("could not be opened: {}", e)))
+}
+
+/// Verifies the signature of a payload opened from `path` when a trust store is set, and checks
+/// that it is a shared object the process can load. The checks run on a sealed memfd copy of the
+/// file, and a file stored as is is loaded from that same copy; otherwise it is decrypted and
+/// decompressed into RAM. Returns the loadable image and how the file was stored.
+fn buffer_payload(
+    path: &str,
+    file: File,
+    context: &PayloadContext,
+) -> Result<(PayloadImage, StoredFormat), String> {
+    // The file could still change after the checks; its copy cannot. Companion copies already
+    // come sealed.
+    let file = if inject::is_sealed(&file) {
+        file
+    } else {
+        inject::sealed_copy(&file).map_err(|e| format!("could not be copied: {}", e))?
+    };
+    let map = FileMap::new(&file).map_err(|e| format!("could not be mapped: {}", e))?;
+    let stored: &[u8] = &map;
+    if let Some(store) = &context.trust_store {
+        let signature = read_module_file(context.api, &signature_path(path));
+        store
+            .verify_signature_file(stored, signature)
+            .map_err(|e| format!("signature check failed: {}", e))?;
+    }
+    let encrypted = crypt::is_encrypted(stored);
+    if !encrypted && Compression::detect(stored) == Compression::None {
+        elf::validate(stored, context.instruction_set).map_err(|e| format!("invalid ELF: {}", e))?;
+        let image = PayloadImage::File(file);
+        return Ok((image, StoredFormat { encrypted, compression: Compression::None }));
+    }
+    let data = if encrypted {
+        let key = context.payload_key().map_err(|e| format!("no payload key: {}", e))?;
+        crypt::decrypt(stored, key).map_err(|e| e.to_string())?
+    } else {
+        stored.to_vec()
+    };
+    // Scrubs its input once decoded, and never frees a partial output as is.
+    let (data, compression) =
+        compress::decompress(data, MAX_DECOMPRESSED_SIZE).map_err(|e| e.to_string())?;
+    let data = Zeroizing::new(data);
+    elf::validate(&data, context.instruction_set).map_err(|e| format!("invalid ELF: {}", e))?;
+    Ok((PayloadImage::Buffered(data), StoredFormat { encrypted, compression }))
+}
+
+/// Keeps a file image open through specialization, which closes every descriptor Zygisk was
+/// not told about. Without `exempt_fd` (API v3 and older), the file is read into RAM instead.
+fn keep_across_specialization(
+    api: &ZygiskApi,
+    image: PayloadImage,
+) -> Result<PayloadImage, String> {
+    match image {
+        PayloadImage::File(mut file) if !api.exempt_fd(file.as_raw_fd()) => {
+            debug!("fd {} could not be exempted, reading it into RAM", file.as_raw_fd());
+            // Sized from the file up front, so no outgrown copy is left behind.
+            let mut data = Zeroizing::new(Vec::new());
+            file.read_to_end(&mut data).map_err(|e| format!("could not be buffered: {}", e))?;
+            Ok(PayloadImage::Buffered(data))
+        },
+        image => Ok(image),
+    }
+}
+
+fn dlopen_payload(path: &str) -> Result<*mut libc::c_void, String> {
+    let c_path = CString::new(path).map_err(|e| e.to_string())?;
+    unsafe {
+        let handle = libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW);
+        if handle.is_null() {
+            Err(dlerror_string())
+        } else {
+            Ok(handle)
         }
     }
 }
 
-fn read_target_config() -> std::io::Result<String> {
-    let f = File::open(CONFIG_PATH)?;
-    let mut reader = BufReader::new(f);
-    let mut line = String::new();
-    reader.read_line(&mut line)?;
-    Ok(line.trim().to_string())
+/// The pending `dlerror()` message.
+fn dlerror_string() -> String {
+    unsafe {
+        let message = libc::dlerror();
+        if message.is_null() {
+            "unknown dynamic linker error".to_string()
+        } else {
+            CStr::from_ptr(message).to_string_lossy().into_owned()
+        }
+    }
+}
+
+/// Opens a file of the module, or a payload. When SELinux or file modes keep this process out,
+/// the root companion opens it instead. Only usable in `pre_app_specialize`.
+fn open_module_file(api: &ZygiskApi, path: &str) -> std::io::Result<File> {
+    match File::open(path) {
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            debug!("{} not readable, asking the companion", path);
+            companion::open_file(api, path)
+        },
+        result => result,
+    }
+}
+
+/// Reads a file of the module, or a payload. When SELinux or file modes keep this process out,
+/// the root companion reads it instead. Only usable in `pre_app_specialize`.
+fn read_module_file(api: &ZygiskApi, path: &str) -> std::io::Result<Vec<u8>> {
+    match read_file_to_memory(path) {
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            debug!("{} not readable, asking the companion", path);
+            companion::request_file(api, path)
+        },
+        result => result,
+    }
+}
+
+/// Reads `loader.conf`, falling back to the line-based `target` file when it does not exist.
+fn read_target_config(api: &ZygiskApi) -> Result<LoaderConfig, ConfigError> {
+    let text = |data: Vec<u8>| {
+        String::from_utf8(data).map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))
+    };
+    match read_module_file(api, CONFIG_PATH) {
+        Ok(data) => Ok(LoaderConfig::parse(&text(data)?)?),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let data = read_module_file(api, LEGACY_CONFIG_PATH)?;
+            Ok(LoaderConfig::parse_legacy(&text(data)?))
+        },
+        Err(e) => Err(e.into()),
+    }
 }
 
 // ARGS PARSING HELPERS
 
+/// Reads a string argument with the JNIEnv of this thread, already attached in the zygote
+/// child. Empty when the JavaVM is unknown or the string cannot be read.
+fn jstring_arg(value: &JString) -> String {
+    let Some(mut env) = JAVA_VM.get().and_then(|vm| vm.get_env().ok()) else {
+        return String::new();
+    };
+    env.get_string(value).map(Into::into).unwrap_or_default()
+}
+
 fn get_process_name_from_args_safe(args: &AppSpecializeArgs) -> String {
-    if let Some(vm) = JAVA_VM.get() {
-        // Fast-Path: Thread already attached in Zygote child process
-        if let Ok(mut env) = vm.get_env() {
-            if let Ok(s) = env.get_string(args.nice_name) {
-                let s_rust: String = s.into();
-                if !s_rust.is_empty() { return s_rust; }
-            }
-        }
-    }
+    let name = jstring_arg(args.nice_name);
+    if !name.is_empty() { return name; }
     let dir = get_app_data_dir_from_args(args);
     if !dir.is_empty() { return extract_package_from_path(&dir); }
     String::new()
 }
 
 fn get_app_data_dir_from_args(args: &AppSpecializeArgs) -> String {
-    if let Some(vm) = JAVA_VM.get() {
-        // Fast-Path: Thread already attached in Zygote child process
-        if let Ok(mut env) = vm.get_env() {
-            if let Ok(j_str) = env.get_string(args.app_data_dir) {
-                return j_str.into();
-            }
-        }
-    }
-    String::new()
+    jstring_arg(args.app_data_dir)
+}
+
+fn get_se_info_from_args(args: &AppSpecializeArgs) -> String {
+    jstring_arg(args.se_info)
+}
+
+fn get_instruction_set_from_args(args: &AppSpecializeArgs) -> String {
+    jstring_arg(args.instruction_set)
 }
 
 fn extract_package_from_path(path: &str) -> String {
@@ -0,0 +1,478 @@
+//! Process name matching for targets.
+
+use std::fmt;
+
+use regex_lite::Regex;
+
+use crate::process::{ProcessInfo, ProcessKind};
+
+/// How a target's `process` pattern is compared against the specializing process name.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum MatchMode {
+    /// The process name equals the pattern, e.g. `com.foo` or `com.foo:remote`.
+    Exact,
+    /// Only the main process of the package, i.e. a name equal to the package.
+    Main,
+    /// The main process and every `package:subprocess` of the package.
+    #[default]
+    Package,
+    /// A shell-style glob over the whole name: `*` matches any run, `?` a single character.
+    Glob,
+    /// A regular expression that must match the whole name.
+    Regex,
+    /// Any process running under the uid of the package, in any user. Covers every package
+    /// of a `sharedUserId` group, whatever their process names are.
+    SharedUid,
+}
+
+impl MatchMode {
+    pub(crate) fn parse(value: &str) -> Option<MatchMode> {
+        match value {
+            "exact" => Some(MatchMode::Exact),
+            "main" => Some(MatchMode::Main),
+            "package" => Some(MatchMode::Package),
+            "glob" => Some(MatchMode::Glob),
+            "regex" => Some(MatchMode::Regex),
+            "shared-uid" => Some(MatchMode::SharedUid),
+            _ => None,
+        }
+    }
+
+    /// Whether the mode matches on the `process` pattern rather than on the package alone.
+    pub(crate) fn uses_pattern(self) -> bool {
+        matches!(self, MatchMode::Exact | MatchMode::Glob | MatchMode::Regex)
+    }
+}
+
+impl fmt::Display for MatchMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MatchMode::Exact => "exact",
+            MatchMode::Main => "main",
+            MatchMode::Package => "package",
+            MatchMode::Glob => "glob",
+            MatchMode::Regex => "regex",
+            MatchMode::SharedUid => "shared-uid",
+        })
+    }
+}
+
+/// A compiled process matcher.
+#[derive(Clone, Debug)]
+pub(crate) enum ProcessMatcher {
+    Exact(String),
+    Package(String),
+    Glob(String),
+    Regex(Regex),
+    SharedUid(String),
+}
+
+impl ProcessMatcher {
+    /// Builds the matcher for `mode`. `Main`, `Package` and `SharedUid` match on `package`; the
+    /// other modes use `pattern`, which falls back to `package` when not set.
+    pub(crate) fn new(
+        mode: MatchMode,
+        package: &str,
+        pattern: Option<&str>,
+    ) -> Result<ProcessMatcher, regex_lite::Error> {
+        let pattern = pattern.unwrap_or(package);
+        Ok(match mode {
+            MatchMode::Exact => ProcessMatcher::Exact(pattern.to_string()),
+            MatchMode::Main => ProcessMatcher::Exact(package.to_string()),
+            MatchMode::Package => ProcessMatcher::Package(package.to_string()),
+            MatchMode::Glob => ProcessMatcher::Glob(pattern.to_string()),
+            MatchMode::Regex => ProcessMatcher::Regex(Regex::new(&format!("^(?:{})$", pattern))?),
+            MatchMode::SharedUid => ProcessMatcher::SharedUid(package.to_string()),
+        })
+    }
+
+    /// Whether the matcher needs [ProcessInfo::packages] to be resolved.
+    pub(crate) fn needs_packages(&self) -> bool {
+        matches!(self, ProcessMatcher::SharedUid(_))
+    }
+
+    pub(crate) fn matches(&self, info: &ProcessInfo) -> bool {
+        let process = info.name.as_str();
+        match self {
+            ProcessMatcher::Exact(name) => process == name,
+            ProcessMatcher::Package(package) => match process.strip_prefix(package.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with(':'),
+                None => false,
+            },
+            ProcessMatcher::Glob(glob) => glob_match(glob.as_bytes(), process.as_bytes()),
+            ProcessMatcher::Regex(regex) => regex.is_match(process),
+            ProcessMatcher::SharedUid(package) => info.packages.iter().any(|p| &p.name == package),
+        }
+    }
+}
+
+impl PartialEq for ProcessMatcher {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (ProcessMatcher::Exact(a), ProcessMatcher::Exact(b))
+            | (ProcessMatcher::Package(a), ProcessMatcher::Package(b))
+            | (ProcessMatcher::Glob(a), ProcessMatcher::Glob(b))
+            | (ProcessMatcher::SharedUid(a), ProcessMatcher::SharedUid(b)) => a == b,
+            (ProcessMatcher::Regex(a), ProcessMatcher::Regex(b)) => a.as_str() == b.as_str(),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for ProcessMatcher {}
+
+impl fmt::Display for ProcessMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessMatcher::Exact(name) => write!(f, "exact `{}`", name),
+            ProcessMatcher::Package(package) => write!(f, "package `{}`", package),
+            ProcessMatcher::Glob(glob) => write!(f, "glob `{}`", glob),
+            ProcessMatcher::Regex(regex) => write!(f, "regex `{}`", regex.as_str()),
+            ProcessMatcher::SharedUid(package) => write!(f, "uid of `{}`", package),
+        }
+    }
+}
+
+/// An inclusive range of ids, written `N` or `N-M` in the config.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct IdRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl IdRange {
+    pub(crate) fn parse(value: &str) -> Option<IdRange> {
+        let (start, end) = match value.split_once('-') {
+            Some((start, end)) => (start.trim().parse().ok()?, end.trim().parse().ok()?),
+            None => {
+                let id = value.trim().parse().ok()?;
+                (id, id)
+            }
+        };
+        (start <= end).then_some(IdRange { start, end })
+    }
+
+    pub(crate) fn contains(&self, id: u32) -> bool {
+        (self.start..=self.end).contains(&id)
+    }
+}
+
+/// Constraints on the uid of a process. Each non-empty list must contain the matching part of
+/// the uid; empty lists accept everything.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct UidFilter {
+    /// Android user ids (`uid / 100000`).
+    pub users: Vec<IdRange>,
+    /// App ids (`uid % 100000`).
+    pub app_ids: Vec<IdRange>,
+    /// Full uids.
+    pub uids: Vec<IdRange>,
+}
+
+impl UidFilter {
+    pub(crate) fn matches(&self, process: &ProcessInfo) -> bool {
+        let allows = |ranges: &[IdRange], id: u32| {
+            ranges.is_empty() || ranges.iter().any(|r| r.contains(id))
+        };
+        allows(&self.users, process.user_id())
+            && allows(&self.app_ids, process.app_id())
+            && allows(&self.uids, process.uid)
+    }
+}
+
+/// Process kinds a target includes or excludes. An empty include list accepts every kind that
+/// is not excluded.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct KindFilter {
+    pub include: Vec<ProcessKind>,
+    pub exclude: Vec<ProcessKind>,
+}
+
+impl KindFilter {
+    pub(crate) fn matches(&self, kind: ProcessKind) -> bool {
+        (self.include.is_empty() || self.include.contains(&kind)) && !self.exclude.contains(&kind)
+    }
+}
+
+/// `seinfo` patterns a target accepts. An empty list accepts every label.
+///
+/// Each pattern is a glob over the whole label, or over a `:`-separated prefix of it, so that
+/// `platform` matches `platform:privapp:targetSdkVersion=29:complete`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct SeInfoFilter {
+    pub patterns: Vec<String>,
+}
+
+impl SeInfoFilter {
+    pub(crate) fn matches(&self, se_info: &str) -> bool {
+        self.patterns.is_empty()
+            || self.patterns.iter().any(|pattern| {
+                let pattern = pattern.as_bytes();
+                let se_info = se_info.as_bytes();
+                glob_match(pattern, se_info)
+                    || (0..se_info.len())
+                        .filter(|&i| se_info[i] == b':')
+                        .any(|i| glob_match(pattern, &se_info[..i]))
+            })
+    }
+}
+
+/// Conditions on how the process was launched. Unset conditions accept every process.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct LaunchConditions {
+    pub top_app_only: bool,
+    pub first_start_only: bool,
+    pub skip_denylisted: bool,
+}
+
+impl LaunchConditions {
+    pub(crate) fn matches(&self, process: &ProcessInfo) -> bool {
+        (!self.top_app_only || process.is_top_app)
+            && (!self.first_start_only || process.is_first_start)
+            && !(self.skip_denylisted && process.on_denylist)
+    }
+}
+
+/// Iterative glob matching with single-star backtracking.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` in the pattern, and the text position it is matched up to.
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::packages::PackageEntry;
+
+    fn matcher(mode: MatchMode, pattern: Option<&str>) -> ProcessMatcher {
+        ProcessMatcher::new(mode, "com.foo", pattern).unwrap()
+    }
+
+    fn named(name: &str) -> ProcessInfo {
+        ProcessInfo {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn exact_mode() {
+        let m = matcher(MatchMode::Exact, None);
+        assert!(m.matches(&named("com.foo")));
+        assert!(!m.matches(&named("com.foobar")));
+        assert!(!m.matches(&named("com.foo:remote")));
+
+        let m = matcher(MatchMode::Exact, Some("com.foo:remote"));
+        assert!(m.matches(&named("com.foo:remote")));
+        assert!(!m.matches(&named("com.foo")));
+        assert!(!m.matches(&named("com.foo:remote2")));
+    }
+
+    #[test]
+    fn main_mode_ignores_pattern() {
+        let m = matcher(MatchMode::Main, Some("com.foo:remote"));
+        assert!(m.matches(&named("com.foo")));
+        assert!(!m.matches(&named("com.foo:remote")));
+        assert!(!m.matches(&named("com.foo.helper")));
+    }
+
+    #[test]
+    fn package_mode() {
+        let m = matcher(MatchMode::Package, None);
+        assert!(m.matches(&named("com.foo")));
+        assert!(m.matches(&named("com.foo:remote")));
+        assert!(!m.matches(&named("com.foobar")));
+        assert!(!m.matches(&named("com.foo.helper")));
+        assert!(!m.matches(&named("org.com.foo")));
+    }
+
+    #[test]
+    fn glob_mode() {
+        let m = matcher(MatchMode::Glob, Some("com.foo*"));
+        assert!(m.matches(&named("com.foo")));
+        assert!(m.matches(&named("com.foobar")));
+        assert!(m.matches(&named("com.foo:remote")));
+        assert!(!m.matches(&named("org.foo")));
+
+        let m = matcher(MatchMode::Glob, Some("com.*:svc?"));
+        assert!(m.matches(&named("com.foo:svc1")));
+        assert!(m.matches(&named("com.a:b:svc2")));
+        assert!(!m.matches(&named("com.foo:svc")));
+        assert!(!m.matches(&named("com.foo:svc12")));
+
+        let m = matcher(MatchMode::Glob, None);
+        assert!(m.matches(&named("com.foo")));
+        assert!(!m.matches(&named("com.foo:remote")));
+    }
+
+    #[test]
+    fn regex_mode_is_anchored() {
+        let m = matcher(MatchMode::Regex, Some(r"com\.foo(:(remote|push))?"));
+        assert!(m.matches(&named("com.foo")));
+        assert!(m.matches(&named("com.foo:push")));
+        assert!(!m.matches(&named("com.foo:other")));
+        assert!(!m.matches(&named("xcom.foo")));
+
+        assert!(ProcessMatcher::new(MatchMode::Regex, "com.foo", Some("(")).is_err());
+    }
+
+    #[test]
+    fn shared_uid_mode() {
+        let m = matcher(MatchMode::SharedUid, None);
+        let entry = |name: &str| PackageEntry {
+            name: name.to_string(),
+            uid: 10125,
+            debuggable: false,
+            data_dir: String::new(),
+            se_info: String::new(),
+            gids: Vec::new(),
+        };
+        let process = ProcessInfo {
+            name: "com.google.process.gapps".to_string(),
+            uid: 10125,
+            packages: vec![entry("com.bar"), entry("com.foo")],
+            ..Default::default()
+        };
+        assert!(m.needs_packages());
+        assert!(m.matches(&process));
+        assert!(!m.matches(&named("com.foo")));
+    }
+
+    #[test]
+    fn uid_filter() {
+        let process = |uid| ProcessInfo {
+            uid,
+            ..Default::default()
+        };
+        assert!(UidFilter::default().matches(&process(1_010_123)));
+
+        let work_profile = UidFilter {
+            users: vec![IdRange::parse("10").unwrap()],
+            ..Default::default()
+        };
+        assert!(work_profile.matches(&process(1_010_123)));
+        assert!(!work_profile.matches(&process(10_123)));
+
+        let app = UidFilter {
+            app_ids: vec![IdRange::parse("10100-10200").unwrap()],
+            uids: vec![
+                IdRange::parse("10123").unwrap(),
+                IdRange::parse("1010150").unwrap(),
+            ],
+            ..Default::default()
+        };
+        assert!(app.matches(&process(10_123)));
+        assert!(app.matches(&process(1_010_150)));
+        assert!(!app.matches(&process(1_010_123)));
+        assert!(!app.matches(&process(10_300)));
+
+        assert_eq!(IdRange::parse("20-10"), None);
+        assert_eq!(IdRange::parse("x"), None);
+    }
+
+    #[test]
+    fn kind_filter() {
+        assert!(KindFilter::default().matches(ProcessKind::Isolated));
+
+        let ui_only = KindFilter {
+            include: vec![ProcessKind::App],
+            ..Default::default()
+        };
+        assert!(ui_only.matches(ProcessKind::App));
+        assert!(!ui_only.matches(ProcessKind::Isolated));
+
+        let no_sandboxes = KindFilter {
+            exclude: vec![ProcessKind::Isolated, ProcessKind::SdkSandbox],
+            ..Default::default()
+        };
+        assert!(no_sandboxes.matches(ProcessKind::AppZygote));
+        assert!(!no_sandboxes.matches(ProcessKind::SdkSandbox));
+    }
+
+    #[test]
+    fn se_info_filter() {
+        assert!(SeInfoFilter::default().matches("default:targetSdkVersion=34:complete"));
+
+        let platform = SeInfoFilter {
+            patterns: vec!["platform".to_string()],
+        };
+        assert!(platform.matches("platform"));
+        assert!(platform.matches("platform:privapp:targetSdkVersion=29:complete"));
+        assert!(!platform.matches("platformx:complete"));
+        assert!(!platform.matches("default:targetSdkVersion=34:complete"));
+
+        let sdk = SeInfoFilter {
+            patterns: vec![
+                "media".to_string(),
+                "default:targetSdkVersion=3?".to_string(),
+            ],
+        };
+        assert!(sdk.matches("default:targetSdkVersion=34:complete"));
+        assert!(sdk.matches("media:privapp"));
+        assert!(!sdk.matches("default:targetSdkVersion=28:complete"));
+
+        let privapp = SeInfoFilter {
+            patterns: vec!["*:privapp".to_string()],
+        };
+        assert!(privapp.matches("platform:privapp:targetSdkVersion=29"));
+        assert!(!privapp.matches("default:targetSdkVersion=34"));
+    }
+
+    #[test]
+    fn launch_conditions() {
+        let background = ProcessInfo {
+            on_denylist: true,
+            ..Default::default()
+        };
+        let foreground = ProcessInfo {
+            is_top_app: true,
+            is_first_start: true,
+            ..Default::default()
+        };
+        assert!(LaunchConditions::default().matches(&background));
+
+        let top_first = LaunchConditions {
+            top_app_only: true,
+            first_start_only: true,
+            ..Default::default()
+        };
+        assert!(top_first.matches(&foreground));
+        assert!(!top_first.matches(&background));
+
+        let not_denylisted = LaunchConditions {
+            skip_denylisted: true,
+            ..Default::default()
+        };
+        assert!(not_denylisted.matches(&foreground));
+        assert!(!not_denylisted.matches(&background));
+    }
+
+    #[test]
+    fn parses_mode_names() {
+        assert_eq!(MatchMode::parse("glob"), Some(MatchMode::Glob));
+        assert_eq!(MatchMode::parse("contains"), None);
+    }
+}
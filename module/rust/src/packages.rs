@@ -0,0 +1,160 @@
+//! Parser for `/data/system/packages.list`.
+//!
+//! Each line describes one installed package:
+//!
+//! ```text
+//! <name> <uid> <debuggable> <data dir> <seinfo> <gids|none> [<profileable> <version> ...]
+//! ```
+//!
+//! The uid is the app id of the owner user; the same package in another user runs under
+//! `user_id * 100000 + uid`. Only the first five fields are required, which keeps older
+//! Android releases working. Trailing fields are ignored.
+
+use std::fs;
+use std::io;
+
+use crate::process::PER_USER_RANGE;
+
+pub(crate) const PACKAGES_LIST_PATH: &str = "/data/system/packages.list";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct PackageEntry {
+    pub name: String,
+    pub uid: u32,
+    pub debuggable: bool,
+    pub data_dir: String,
+    pub se_info: String,
+    pub gids: Vec<u32>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct PackageList {
+    pub entries: Vec<PackageEntry>,
+}
+
+impl PackageList {
+    /// Reads and parses the list. Only readable with root privileges, i.e. in
+    /// `pre_app_specialize` or the companion.
+    pub(crate) fn load(path: &str) -> io::Result<PackageList> {
+        Ok(PackageList::parse(&fs::read_to_string(path)?))
+    }
+
+    /// Parses the list, skipping malformed lines.
+    pub(crate) fn parse(text: &str) -> PackageList {
+        let mut entries = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match parse_entry(line) {
+                Some(entry) => entries.push(entry),
+                None => warn!("packages.list:{}: malformed entry skipped", index + 1),
+            }
+        }
+        PackageList { entries }
+    }
+
+    /// All packages running under `uid` in any user, i.e. members of the same shared uid.
+    pub(crate) fn packages_for_uid(&self, uid: u32) -> impl Iterator<Item = &PackageEntry> {
+        let app_id = uid % PER_USER_RANGE;
+        self.entries.iter().filter(move |e| e.uid == app_id)
+    }
+}
+
+fn parse_entry(line: &str) -> Option<PackageEntry> {
+    let mut fields = line.split_whitespace();
+    let name = fields.next()?.to_string();
+    let uid = fields.next()?.parse().ok()?;
+    let debuggable = match fields.next()? {
+        "0" => false,
+        "1" => true,
+        _ => return None,
+    };
+    let data_dir = fields.next()?.to_string();
+    let se_info = fields.next()?.to_string();
+    let gids = match fields.next() {
+        None | Some("none") => Vec::new(),
+        Some(gids) => gids
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .ok()?,
+    };
+    Some(PackageEntry {
+        name,
+        uid,
+        debuggable,
+        data_dir,
+        se_info,
+        gids,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fixture(name: &str) -> PackageList {
+        PackageList::load(&format!(
+            "{}/tests/fixtures/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        ))
+        .unwrap()
+    }
+
+    fn get<'a>(list: &'a PackageList, name: &str) -> &'a PackageEntry {
+        list.entries.iter().find(|e| e.name == name).unwrap()
+    }
+
+    #[test]
+    fn parses_current_format() {
+        let list = fixture("packages.list");
+        assert_eq!(list.entries.len(), 4);
+
+        let gms = get(&list, "com.google.android.gms");
+        assert_eq!(gms.uid, 10125);
+        assert!(!gms.debuggable);
+        assert_eq!(gms.data_dir, "/data/user/0/com.google.android.gms");
+        assert_eq!(
+            gms.se_info,
+            "default:privapp:targetSdkVersion=34:partneruid:complete"
+        );
+        assert_eq!(gms.gids, [3002, 3003, 3001, 1007]);
+
+        let debug = get(&list, "com.example.debug");
+        assert!(debug.debuggable);
+        assert!(debug.gids.is_empty());
+    }
+
+    #[test]
+    fn resolves_shared_uid_in_any_user() {
+        let list = fixture("packages.list");
+        let names = |uid| {
+            list.packages_for_uid(uid)
+                .map(|e| e.name.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(10125),
+            ["com.google.android.gms", "com.google.android.gsf"]
+        );
+        assert_eq!(
+            names(1_010_125),
+            ["com.google.android.gms", "com.google.android.gsf"]
+        );
+        assert_eq!(names(1000), ["com.android.settings"]);
+        assert!(names(10999).is_empty());
+    }
+
+    #[test]
+    fn parses_legacy_format_and_skips_malformed_lines() {
+        let list = fixture("packages-legacy.list");
+        let names: Vec<_> = list.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["com.android.shell", "com.example.old"]);
+
+        let shell = get(&list, "com.android.shell");
+        assert_eq!(shell.se_info, "platform:privapp");
+        assert_eq!(shell.gids, [3002, 3003]);
+    }
+}
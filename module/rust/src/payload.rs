@@ -0,0 +1,237 @@
+//! Payload resolution, buffering and ordered, dependency-aware loading.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use zeroize::Zeroizing;
+
+use crate::compress::Compression;
+use crate::config::PayloadSpec;
+use crate::inject::Image;
+
+/// Directory under the config directory holding one subdirectory per instruction set, e.g.
+/// `payloads/arm64/libhook.so` and `payloads/arm/libhook.so`.
+pub(crate) const ABI_PAYLOADS_DIR: &str = "payloads";
+
+/// The files a payload name may refer to, in order of preference. Absolute names are used as
+/// is. Relative names prefer the build for the process ABI in `payloads/<instruction set>/`,
+/// then fall back to the config directory itself. Which one exists is only known once opened:
+/// the zygote child may not be allowed to look.
+pub(crate) fn payload_paths(config_dir: &str, name: &str, instruction_set: &str) -> Vec<String> {
+    if name.starts_with('/') {
+        return vec![name.to_string()];
+    }
+    let mut paths = Vec::with_capacity(2);
+    if !instruction_set.is_empty() {
+        paths.push(format!(
+            "{}/{}/{}/{}",
+            config_dir, ABI_PAYLOADS_DIR, instruction_set, name
+        ));
+    }
+    paths.push(format!("{}/{}", config_dir, name));
+    paths
+}
+
+/// Opens the first of [payload_paths] that exists, returning its path with the result. Only a
+/// missing file moves on to the next one: any other error, such as a denied open the companion
+/// could not serve either, is returned rather than silently loading the fallback build.
+pub(crate) fn open_payload<T>(
+    paths: Vec<String>,
+    open: impl Fn(&str) -> io::Result<T>,
+) -> (String, io::Result<T>) {
+    let mut paths = paths.into_iter().peekable();
+    loop {
+        let path = paths.next().expect("payload_paths is never empty");
+        match open(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound && paths.peek().is_some() => {}
+            result => return (path, result),
+        }
+    }
+}
+
+/// How a payload file is stored on disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct StoredFormat {
+    pub encrypted: bool,
+    pub compression: Compression,
+}
+
+impl fmt::Display for StoredFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.encrypted {
+            write!(f, "encrypted, ")?;
+        }
+        write!(f, "{}", self.compression)
+    }
+}
+
+/// A checked payload image, kept until it is loaded.
+pub(crate) enum PayloadImage {
+    /// Decoded into RAM, or read there when its file could not be kept open. Zeroed when
+    /// dropped.
+    Buffered(Zeroizing<Vec<u8>>),
+    /// A file stored as is, as the sealed memfd its checks ran on, opened before
+    /// specialization. Nothing else is copied until a strategy needs it.
+    File(File),
+}
+
+impl PayloadImage {
+    pub(crate) fn image(&self) -> Image<'_> {
+        match self {
+            PayloadImage::Buffered(data) => Image::Bytes(data),
+            PayloadImage::File(file) => Image::File(file),
+        }
+    }
+}
+
+impl fmt::Display for PayloadImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayloadImage::Buffered(data) => write!(f, "{} bytes in RAM", data.len()),
+            PayloadImage::File(file) => write!(f, "sealed memfd {}", file.as_raw_fd()),
+        }
+    }
+}
+
+/// A payload of the matched target, checked during `pre_app_specialize`.
+pub(crate) struct PayloadBuffer {
+    pub spec: PayloadSpec,
+    /// Source path, for logging.
+    pub path: String,
+    /// The payload image, or why it could not be used.
+    pub data: Result<PayloadImage, String>,
+}
+
+impl AsRef<PayloadSpec> for PayloadBuffer {
+    fn as_ref(&self) -> &PayloadSpec {
+        &self.spec
+    }
+}
+
+/// Loads `payloads` in order with `load`, which returns a detail to log on success or the
+/// reason of the failure. A payload is skipped when one of its dependencies did not load, and
+/// then counts as failed for its own dependents. Every outcome is logged.
+///
+/// Returns the names of the payloads that loaded.
+pub(crate) fn load_in_order<T, F>(payloads: &[T], mut load: F) -> Vec<&str>
+where
+    T: AsRef<PayloadSpec>,
+    F: FnMut(&T) -> Result<String, String>,
+{
+    let mut loaded: Vec<&str> = Vec::with_capacity(payloads.len());
+    for payload in payloads {
+        let spec = payload.as_ref();
+        if let Some(missing) = spec
+            .requires
+            .iter()
+            .find(|dep| !loaded.contains(&dep.as_str()))
+        {
+            error!(
+                "Injection of {} skipped: dependency {} did not load",
+                spec.name, missing
+            );
+            continue;
+        }
+        match load(payload) {
+            Ok(detail) => {
+                info!("Injection of {} success! {}", spec.name, detail);
+                loaded.push(&spec.name);
+            }
+            Err(err) => error!("Injection of {} failed: {}", spec.name, err),
+        }
+    }
+    loaded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn spec(name: &str, requires: &[&str]) -> PayloadSpec {
+        PayloadSpec {
+            name: name.to_string(),
+            requires: requires.iter().map(|r| r.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn opens_the_first_existing_payload() {
+        let dir = std::env::temp_dir().join(format!("zygiskloader-abi-{}", std::process::id()));
+        let arm64 = dir.join(ABI_PAYLOADS_DIR).join("arm64");
+        std::fs::create_dir_all(&arm64).unwrap();
+        std::fs::write(arm64.join("libhook.so"), b"").unwrap();
+        let config_dir = dir.to_str().unwrap();
+        let open = |name: &str, abi: &str| {
+            let (path, file) = open_payload(payload_paths(config_dir, name, abi), |path| {
+                File::open(path)
+            });
+            (path, file.map(drop).map_err(|e| e.kind()))
+        };
+
+        assert_eq!(
+            open("libhook.so", "arm64"),
+            (format!("{}/payloads/arm64/libhook.so", config_dir), Ok(()))
+        );
+        assert_eq!(
+            open("libhook.so", "arm"),
+            (
+                format!("{}/libhook.so", config_dir),
+                Err(io::ErrorKind::NotFound)
+            )
+        );
+
+        // A build that cannot be looked at is not taken for a missing one.
+        let (path, result) = open_payload(payload_paths(config_dir, "libhook.so", "arm"), |_| {
+            Err::<(), _>(io::Error::from(io::ErrorKind::PermissionDenied))
+        });
+        assert_eq!(path, format!("{}/payloads/arm/libhook.so", config_dir));
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lists_abi_specific_payloads_first() {
+        assert_eq!(
+            payload_paths("/config", "libhook.so", "arm64"),
+            ["/config/payloads/arm64/libhook.so", "/config/libhook.so"]
+        );
+        assert_eq!(
+            payload_paths("/config", "libhook.so", ""),
+            ["/config/libhook.so"]
+        );
+        assert_eq!(
+            payload_paths("/config", "/system/lib64/libx.so", "arm64"),
+            ["/system/lib64/libx.so"]
+        );
+    }
+
+    #[test]
+    fn loads_in_order_and_blocks_dependents() {
+        let payloads = [
+            spec("base.so", &[]),
+            spec("broken.so", &["base.so"]),
+            spec("feature.so", &["base.so"]),
+            spec("addon.so", &["broken.so"]),
+            spec("addon2.so", &["addon.so", "feature.so"]),
+            spec("standalone.so", &[]),
+        ];
+        let mut attempted = Vec::new();
+        let loaded = load_in_order(&payloads, |p| {
+            attempted.push(p.name.clone());
+            if p.name == "broken.so" {
+                Err("dlopen failed".to_string())
+            } else {
+                Ok(String::new())
+            }
+        });
+
+        assert_eq!(loaded, ["base.so", "feature.so", "standalone.so"]);
+        assert_eq!(
+            attempted,
+            ["base.so", "broken.so", "feature.so", "standalone.so"]
+        );
+    }
+}
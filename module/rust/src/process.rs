@@ -0,0 +1,176 @@
+//! Facts about the process being specialized, gathered once in `pre_app_specialize`.
+
+use std::fmt;
+
+use crate::packages::PackageEntry;
+
+/// Number of uids reserved for each Android user, as in `UserHandle.PER_USER_RANGE`.
+pub(crate) const PER_USER_RANGE: u32 = 100_000;
+
+// App id ranges from `android.os.Process`.
+const FIRST_APPLICATION_UID: u32 = 10_000;
+const LAST_APPLICATION_UID: u32 = 19_999;
+const FIRST_SDK_SANDBOX_UID: u32 = 20_000;
+const LAST_SDK_SANDBOX_UID: u32 = 29_999;
+const FIRST_APP_ZYGOTE_ISOLATED_UID: u32 = 90_000;
+const LAST_APP_ZYGOTE_ISOLATED_UID: u32 = 98_999;
+const FIRST_ISOLATED_UID: u32 = 99_000;
+const LAST_ISOLATED_UID: u32 = 99_999;
+const WEBVIEW_ZYGOTE_UID: u32 = 1053;
+
+/// What kind of process a specialization produces.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum ProcessKind {
+    /// A regular app process, main or `:subprocess`.
+    #[default]
+    App,
+    /// An app running with a system uid (below 10000), e.g. Settings.
+    System,
+    /// An isolated service process, including webview renderers.
+    Isolated,
+    /// The app zygote of an app that declares `android:zygotePreloadName`.
+    AppZygote,
+    /// An isolated process forked from an app zygote.
+    AppZygoteIsolated,
+    /// The webview zygote.
+    WebViewZygote,
+    /// An SDK runtime sandbox process.
+    SdkSandbox,
+}
+
+impl ProcessKind {
+    pub(crate) fn classify(uid: u32, is_child_zygote: bool) -> ProcessKind {
+        let app_id = uid % PER_USER_RANGE;
+        if is_child_zygote {
+            return if app_id == WEBVIEW_ZYGOTE_UID {
+                ProcessKind::WebViewZygote
+            } else {
+                ProcessKind::AppZygote
+            };
+        }
+        match app_id {
+            FIRST_APPLICATION_UID..=LAST_APPLICATION_UID => ProcessKind::App,
+            FIRST_SDK_SANDBOX_UID..=LAST_SDK_SANDBOX_UID => ProcessKind::SdkSandbox,
+            FIRST_APP_ZYGOTE_ISOLATED_UID..=LAST_APP_ZYGOTE_ISOLATED_UID => {
+                ProcessKind::AppZygoteIsolated
+            }
+            FIRST_ISOLATED_UID..=LAST_ISOLATED_UID => ProcessKind::Isolated,
+            id if id < FIRST_APPLICATION_UID => ProcessKind::System,
+            _ => ProcessKind::App,
+        }
+    }
+
+    pub(crate) fn parse(value: &str) -> Option<ProcessKind> {
+        match value {
+            "app" => Some(ProcessKind::App),
+            "system" => Some(ProcessKind::System),
+            "isolated" => Some(ProcessKind::Isolated),
+            "app-zygote" => Some(ProcessKind::AppZygote),
+            "app-zygote-isolated" => Some(ProcessKind::AppZygoteIsolated),
+            "webview-zygote" => Some(ProcessKind::WebViewZygote),
+            "sdk-sandbox" => Some(ProcessKind::SdkSandbox),
+            _ => None,
+        }
+    }
+
+    /// Whether the process has no app data directory of its own to write to.
+    pub(crate) fn is_isolated(self) -> bool {
+        matches!(
+            self,
+            ProcessKind::Isolated | ProcessKind::AppZygoteIsolated | ProcessKind::SdkSandbox
+        )
+    }
+}
+
+impl fmt::Display for ProcessKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ProcessKind::App => "app",
+            ProcessKind::System => "system",
+            ProcessKind::Isolated => "isolated",
+            ProcessKind::AppZygote => "app-zygote",
+            ProcessKind::AppZygoteIsolated => "app-zygote-isolated",
+            ProcessKind::WebViewZygote => "webview-zygote",
+            ProcessKind::SdkSandbox => "sdk-sandbox",
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ProcessInfo {
+    pub name: String,
+    pub uid: u32,
+    pub kind: ProcessKind,
+    /// SELinux `seinfo` label, e.g. `platform:privapp:targetSdkVersion=34:complete`.
+    pub se_info: String,
+    /// `packages.list` entries sharing the process uid. Only resolved when a target needs them.
+    pub packages: Vec<PackageEntry>,
+    /// Launched as the foreground app. Always `false` where zygote does not report it.
+    pub is_top_app: bool,
+    /// [StateFlags::PROCESS_IS_FIRST_STARTED](crate::StateFlags::PROCESS_IS_FIRST_STARTED).
+    pub is_first_start: bool,
+    /// [StateFlags::PROCESS_ON_DENYLIST](crate::StateFlags::PROCESS_ON_DENYLIST).
+    pub on_denylist: bool,
+    /// ABI of the process as named by ART: `arm`, `arm64`, `x86`, `x86_64` or `riscv64`.
+    pub instruction_set: String,
+}
+
+impl ProcessInfo {
+    /// The Android user (profile) the process runs in, e.g. 0 for the owner.
+    pub(crate) fn user_id(&self) -> u32 {
+        self.uid / PER_USER_RANGE
+    }
+
+    /// The uid with the user part stripped, shared by all instances of the same app.
+    pub(crate) fn app_id(&self) -> u32 {
+        self.uid % PER_USER_RANGE
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn classifies_by_uid() {
+        assert_eq!(ProcessKind::classify(10_123, false), ProcessKind::App);
+        assert_eq!(ProcessKind::classify(1_010_123, false), ProcessKind::App);
+        assert_eq!(ProcessKind::classify(1000, false), ProcessKind::System);
+        assert_eq!(
+            ProcessKind::classify(20_123, false),
+            ProcessKind::SdkSandbox
+        );
+        assert_eq!(
+            ProcessKind::classify(90_005, false),
+            ProcessKind::AppZygoteIsolated
+        );
+        assert_eq!(
+            ProcessKind::classify(1_099_005, false),
+            ProcessKind::Isolated
+        );
+    }
+
+    #[test]
+    fn classifies_child_zygotes() {
+        assert_eq!(ProcessKind::classify(10_123, true), ProcessKind::AppZygote);
+        assert_eq!(
+            ProcessKind::classify(1053, true),
+            ProcessKind::WebViewZygote
+        );
+    }
+
+    #[test]
+    fn parses_display_names() {
+        for kind in [
+            ProcessKind::App,
+            ProcessKind::System,
+            ProcessKind::Isolated,
+            ProcessKind::AppZygote,
+            ProcessKind::AppZygoteIsolated,
+            ProcessKind::WebViewZygote,
+            ProcessKind::SdkSandbox,
+        ] {
+            assert_eq!(ProcessKind::parse(&kind.to_string()), Some(kind));
+        }
+    }
+}
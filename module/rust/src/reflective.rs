@@ -0,0 +1,1177 @@
+//! Fileless, reflective loading of payload images.
+//!
+//! The image is mapped into anonymous memory straight from its RAM buffer: segments are copied
+//! in, relocations (REL, RELA and RELR) are applied, imports are resolved with `dlsym` against
+//! the image's `DT_NEEDED` libraries, and the init functions run. The system linker never opens
+//! a file, so no file or `(deleted)` entry shows up in `/proc/self/maps`.
+//!
+//! The linker does not know about the image either: `dlsym` and `dladdr` cannot see it, its
+//! destructors never run, and symbols are looked up with [MappedImage::symbol] instead.
+//! Thread-local storage is supported on x86_64 (general and local dynamic models); payloads with
+//! a TLS segment are rejected elsewhere. Unwind tables are registered with `__register_frame`
+//! when the unwinder provides it.
+
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::fmt;
+use std::ptr;
+
+use crate::elf::{self, ElfError, ProgramHeader, PT_DYNAMIC, PT_LOAD};
+
+const PT_TLS: u32 = 7;
+const PT_GNU_EH_FRAME: u32 = 0x6474_e550;
+const PT_GNU_RELRO: u32 = 0x6474_e552;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const DT_NULL: usize = 0;
+const DT_NEEDED: usize = 1;
+const DT_PLTRELSZ: usize = 2;
+const DT_HASH: usize = 4;
+const DT_STRTAB: usize = 5;
+const DT_SYMTAB: usize = 6;
+const DT_RELA: usize = 7;
+const DT_RELASZ: usize = 8;
+const DT_INIT: usize = 12;
+const DT_REL: usize = 17;
+const DT_RELSZ: usize = 18;
+const DT_PLTREL: usize = 20;
+const DT_TEXTREL: usize = 22;
+const DT_JMPREL: usize = 23;
+const DT_INIT_ARRAY: usize = 25;
+const DT_INIT_ARRAYSZ: usize = 27;
+const DT_RELRSZ: usize = 35;
+const DT_RELR: usize = 36;
+const DT_ANDROID_REL: usize = 0x6000_000f;
+const DT_ANDROID_RELA: usize = 0x6000_0011;
+const DT_GNU_HASH: usize = 0x6fff_fef5;
+const DT_ANDROID_RELR: usize = 0x6fff_e000;
+const DT_ANDROID_RELRSZ: usize = 0x6fff_e001;
+
+const SHN_UNDEF: u16 = 0;
+const STB_WEAK: u8 = 2;
+
+#[derive(Debug)]
+pub(crate) enum LoadError {
+    Elf(ElfError),
+    /// `mmap` or `mprotect` failed.
+    Memory(&'static str, std::io::Error),
+    /// A required dynamic table is missing.
+    BadDynamic(&'static str),
+    /// A segment, table or relocation lies outside the image, or its bounds overflow.
+    OutsideImage(&'static str),
+    /// Android packed (APS2) relocations, which this loader does not decode.
+    PackedRelocations,
+    TextRelocations,
+    UnsupportedTls,
+    UnsupportedRelocation(u32),
+    NeededLibrary(String, String),
+    UnresolvedSymbol(String),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Elf(e) => write!(f, "{}", e),
+            LoadError::Memory(call, e) => write!(f, "{} failed: {}", call, e),
+            LoadError::BadDynamic(what) => write!(f, "invalid dynamic section: {}", what),
+            LoadError::OutsideImage(what) => write!(f, "{} lies outside the image", what),
+            LoadError::PackedRelocations => write!(f, "packed relocations are not supported"),
+            LoadError::TextRelocations => write!(f, "text relocations are not supported"),
+            LoadError::UnsupportedTls => {
+                write!(
+                    f,
+                    "thread-local storage is not supported on this architecture"
+                )
+            }
+            LoadError::UnsupportedRelocation(kind) => {
+                write!(f, "unsupported relocation type {}", kind)
+            }
+            LoadError::NeededLibrary(name, e) => write!(f, "cannot load {}: {}", name, e),
+            LoadError::UnresolvedSymbol(name) => write!(f, "unresolved symbol {}", name),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<ElfError> for LoadError {
+    fn from(e: ElfError) -> Self {
+        LoadError::Elf(e)
+    }
+}
+
+/// `ElfW(Dyn)`.
+#[repr(C)]
+struct Dyn {
+    tag: usize,
+    val: usize,
+}
+
+/// `ElfW(Sym)`.
+#[cfg(target_pointer_width = "64")]
+#[repr(C)]
+struct Sym {
+    st_name: u32,
+    st_info: u8,
+    st_other: u8,
+    st_shndx: u16,
+    st_value: usize,
+    st_size: usize,
+}
+
+/// `ElfW(Sym)`.
+#[cfg(target_pointer_width = "32")]
+#[repr(C)]
+struct Sym {
+    st_name: u32,
+    st_value: usize,
+    st_size: usize,
+    st_info: u8,
+    st_other: u8,
+    st_shndx: u16,
+}
+
+#[cfg(target_pointer_width = "64")]
+fn r_sym(info: usize) -> usize {
+    info >> 32
+}
+
+#[cfg(target_pointer_width = "64")]
+fn r_type(info: usize) -> u32 {
+    info as u32
+}
+
+#[cfg(target_pointer_width = "32")]
+fn r_sym(info: usize) -> usize {
+    info >> 8
+}
+
+#[cfg(target_pointer_width = "32")]
+fn r_type(info: usize) -> u32 {
+    info as u32 & 0xff
+}
+
+/// What a relocation type computes, independent of the architecture.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Reloc {
+    None,
+    /// S + A.
+    Absolute,
+    /// S, plus the addend of RELA entries.
+    Symbol,
+    /// B + A.
+    Relative,
+    /// Calls the resolver at B + A.
+    IRelative,
+    /// TLS module id of the symbol.
+    TlsModule,
+    /// Offset of the symbol in its TLS block.
+    TlsOffset,
+}
+
+#[cfg(target_arch = "x86_64")]
+fn classify(kind: u32) -> Option<Reloc> {
+    match kind {
+        0 => Some(Reloc::None),
+        1 => Some(Reloc::Absolute),
+        6 | 7 => Some(Reloc::Symbol),
+        8 => Some(Reloc::Relative),
+        16 => Some(Reloc::TlsModule),
+        17 => Some(Reloc::TlsOffset),
+        37 => Some(Reloc::IRelative),
+        _ => None,
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn classify(kind: u32) -> Option<Reloc> {
+    match kind {
+        0 => Some(Reloc::None),
+        257 => Some(Reloc::Absolute),
+        1025 | 1026 => Some(Reloc::Symbol),
+        1027 => Some(Reloc::Relative),
+        1032 => Some(Reloc::IRelative),
+        _ => None,
+    }
+}
+
+#[cfg(target_arch = "arm")]
+fn classify(kind: u32) -> Option<Reloc> {
+    match kind {
+        0 => Some(Reloc::None),
+        2 => Some(Reloc::Absolute),
+        21 | 22 => Some(Reloc::Symbol),
+        23 => Some(Reloc::Relative),
+        160 => Some(Reloc::IRelative),
+        _ => None,
+    }
+}
+
+#[cfg(target_arch = "x86")]
+fn classify(kind: u32) -> Option<Reloc> {
+    match kind {
+        0 => Some(Reloc::None),
+        1 => Some(Reloc::Absolute),
+        6 | 7 => Some(Reloc::Symbol),
+        8 => Some(Reloc::Relative),
+        42 => Some(Reloc::IRelative),
+        _ => None,
+    }
+}
+
+#[cfg(target_arch = "riscv64")]
+fn classify(kind: u32) -> Option<Reloc> {
+    match kind {
+        0 => Some(Reloc::None),
+        2 => Some(Reloc::Absolute),
+        3 => Some(Reloc::Relative),
+        5 => Some(Reloc::Symbol),
+        58 => Some(Reloc::IRelative),
+        _ => None,
+    }
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+fn page_down(value: usize, page: usize) -> usize {
+    value & !(page - 1)
+}
+
+fn page_up(value: usize, page: usize) -> usize {
+    (value + page - 1) & !(page - 1)
+}
+
+/// A header value as an address or size; ones that do not fit cannot lie inside the image.
+fn to_usize(value: u64) -> Result<usize, LoadError> {
+    usize::try_from(value).map_err(|_| LoadError::OutsideImage("segment"))
+}
+
+/// Where the virtual address range `[min_vaddr, min_vaddr + size)` of an image is mapped.
+#[derive(Clone, Copy)]
+struct Layout {
+    base: usize,
+    min_vaddr: usize,
+    size: usize,
+}
+
+impl Layout {
+    /// Difference between run-time addresses and the image's virtual addresses.
+    fn bias(&self) -> usize {
+        self.base.wrapping_sub(self.min_vaddr)
+    }
+
+    /// Run-time address of the `len` bytes at virtual address `vaddr`, which must all lie
+    /// inside the image. `what` names them in the error.
+    fn address(&self, vaddr: usize, len: usize, what: &'static str) -> Result<usize, LoadError> {
+        vaddr
+            .checked_sub(self.min_vaddr)
+            .filter(|offset| offset.checked_add(len).is_some_and(|end| end <= self.size))
+            .map(|offset| self.base + offset)
+            .ok_or(LoadError::OutsideImage(what))
+    }
+}
+
+/// An image mapped by [load]. The mapping is never released: code from it may run at any time.
+pub(crate) struct MappedImage {
+    layout: Layout,
+    /// Difference between run-time addresses and the image's virtual addresses.
+    bias: usize,
+    symtab: *const Sym,
+    strtab: *const c_char,
+    gnu_hash: Option<*const u32>,
+    hash: Option<*const u32>,
+}
+
+impl fmt::Display for MappedImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Layout { base, size, .. } = self.layout;
+        write!(f, "{:#x}-{:#x}", base, base + size)
+    }
+}
+
+/// The dynamic section entries the loader acts on.
+#[derive(Default)]
+struct DynamicInfo {
+    needed: Vec<usize>,
+    strtab: usize,
+    symtab: usize,
+    hash: Option<usize>,
+    gnu_hash: Option<usize>,
+    rela: (usize, usize),
+    rel: (usize, usize),
+    relr: (usize, usize),
+    jmprel: (usize, usize),
+    pltrel_is_rela: bool,
+    init: usize,
+    init_array: (usize, usize),
+}
+
+impl DynamicInfo {
+    /// Reads the dynamic array up to its `DT_NULL`, which must lie within `dynamic`.
+    fn read(dynamic: &[Dyn]) -> Result<DynamicInfo, LoadError> {
+        let mut info = DynamicInfo {
+            pltrel_is_rela: cfg!(any(
+                target_arch = "x86_64",
+                target_arch = "aarch64",
+                target_arch = "riscv64"
+            )),
+            ..DynamicInfo::default()
+        };
+        let mut entries = dynamic.iter();
+        loop {
+            let entry = entries
+                .next()
+                .ok_or(LoadError::BadDynamic("missing DT_NULL"))?;
+            match entry.tag {
+                DT_NULL => break,
+                DT_NEEDED => info.needed.push(entry.val),
+                DT_STRTAB => info.strtab = entry.val,
+                DT_SYMTAB => info.symtab = entry.val,
+                DT_HASH => info.hash = Some(entry.val),
+                DT_GNU_HASH => info.gnu_hash = Some(entry.val),
+                DT_RELA => info.rela.0 = entry.val,
+                DT_RELASZ => info.rela.1 = entry.val,
+                DT_REL => info.rel.0 = entry.val,
+                DT_RELSZ => info.rel.1 = entry.val,
+                DT_RELR | DT_ANDROID_RELR => info.relr.0 = entry.val,
+                DT_RELRSZ | DT_ANDROID_RELRSZ => info.relr.1 = entry.val,
+                DT_JMPREL => info.jmprel.0 = entry.val,
+                DT_PLTRELSZ => info.jmprel.1 = entry.val,
+                DT_PLTREL => info.pltrel_is_rela = entry.val == DT_RELA,
+                DT_INIT => info.init = entry.val,
+                DT_INIT_ARRAY => info.init_array.0 = entry.val,
+                DT_INIT_ARRAYSZ => info.init_array.1 = entry.val,
+                DT_TEXTREL => return Err(LoadError::TextRelocations),
+                DT_ANDROID_REL | DT_ANDROID_RELA => return Err(LoadError::PackedRelocations),
+                _ => {}
+            }
+        }
+        if info.strtab == 0 || info.symtab == 0 {
+            return Err(LoadError::BadDynamic("missing DT_STRTAB or DT_SYMTAB"));
+        }
+        Ok(info)
+    }
+}
+
+/// Releases a mapping on failure, unless it has been handed over to a [MappedImage].
+struct Mapping {
+    base: *mut u8,
+    size: usize,
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if !self.base.is_null() {
+            unsafe { libc::munmap(self.base.cast(), self.size) };
+        }
+    }
+}
+
+/// Maps, relocates and initializes the shared object in `data`. The image need not have passed
+/// [elf::validate]: every offset and address it holds is checked before use.
+pub(crate) fn load(data: &[u8]) -> Result<MappedImage, LoadError> {
+    let headers = elf::program_headers(data)?;
+    let page = page_size();
+    let loads: Vec<&ProgramHeader> = headers.iter().filter(|h| h.p_type == PT_LOAD).collect();
+    if loads.is_empty() {
+        return Err(ElfError::NoLoadSegment.into());
+    }
+    let mut ends = Vec::with_capacity(loads.len());
+    for header in &loads {
+        if header.p_filesz > header.p_memsz {
+            return Err(LoadError::OutsideImage("segment file size"));
+        }
+        let end = header.p_vaddr.checked_add(header.p_memsz);
+        ends.push(end.ok_or(LoadError::OutsideImage("segment"))?);
+    }
+    let min_vaddr = page_down(
+        to_usize(loads.iter().map(|h| h.p_vaddr).min().unwrap())?,
+        page,
+    );
+    let max_vaddr = to_usize(ends.into_iter().max().unwrap())?
+        .checked_add(page - 1)
+        .map(|end| page_down(end, page))
+        .ok_or(LoadError::OutsideImage("segment"))?;
+    let align = loads
+        .iter()
+        .map(|h| h.p_align as usize)
+        .filter(|a| a.is_power_of_two())
+        .fold(page, usize::max);
+    let size = max_vaddr
+        .checked_sub(min_vaddr)
+        .ok_or(LoadError::OutsideImage("segment"))?;
+
+    // Reserve extra room so the image can start on its strictest segment alignment.
+    let reserved = size
+        .checked_add(align - page)
+        .ok_or(LoadError::OutsideImage("segment alignment"))?;
+    let raw = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            reserved,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if raw == libc::MAP_FAILED {
+        return Err(LoadError::Memory("mmap", std::io::Error::last_os_error()));
+    }
+    let start = page_up(raw as usize, align);
+    unsafe {
+        if start > raw as usize {
+            libc::munmap(raw, start - raw as usize);
+        }
+        let end = raw as usize + reserved;
+        if end > start + size {
+            libc::munmap((start + size) as *mut c_void, end - start - size);
+        }
+    }
+    let mut mapping = Mapping {
+        base: start as *mut u8,
+        size,
+    };
+    let layout = Layout {
+        base: start,
+        min_vaddr,
+        size,
+    };
+    let bias = layout.bias();
+
+    for (index, header) in headers.iter().enumerate() {
+        if header.p_type != PT_LOAD {
+            continue;
+        }
+        let len = header.p_filesz as usize;
+        let source = usize::try_from(header.p_offset)
+            .ok()
+            .and_then(|offset| data.get(offset..offset.checked_add(len)?))
+            .ok_or(ElfError::SegmentOutOfBounds(index))?;
+        let target = layout.address(header.p_vaddr as usize, len, "segment")?;
+        unsafe {
+            ptr::copy_nonoverlapping(source.as_ptr(), target as *mut u8, len);
+        }
+    }
+
+    let dynamic = headers
+        .iter()
+        .find(|h| h.p_type == PT_DYNAMIC)
+        .ok_or(ElfError::NoDynamicSegment)?;
+    // Read from the loaded segments, so it has to lie inside one of them.
+    let in_load = loads.iter().any(|h| {
+        dynamic.p_vaddr >= h.p_vaddr
+            && dynamic
+                .p_vaddr
+                .checked_add(dynamic.p_memsz)
+                .is_some_and(|end| end <= h.p_vaddr + h.p_filesz)
+    });
+    if !in_load {
+        return Err(LoadError::OutsideImage("PT_DYNAMIC"));
+    }
+    let entries = dynamic.p_memsz as usize / std::mem::size_of::<Dyn>();
+    let info = unsafe {
+        let address = layout.address(
+            dynamic.p_vaddr as usize,
+            dynamic.p_memsz as usize,
+            "PT_DYNAMIC",
+        )?;
+        DynamicInfo::read(std::slice::from_raw_parts(address as *const Dyn, entries))?
+    };
+    let image = MappedImage {
+        layout,
+        bias,
+        symtab: layout.address(info.symtab, std::mem::size_of::<Sym>(), "DT_SYMTAB")? as *const Sym,
+        strtab: layout.address(info.strtab, 1, "DT_STRTAB")? as *const c_char,
+        gnu_hash: info
+            .gnu_hash
+            .map(|addr| layout.address(addr, 16, "DT_GNU_HASH"))
+            .transpose()?
+            .map(|addr| addr as *const u32),
+        hash: info
+            .hash
+            .map(|addr| layout.address(addr, 8, "DT_HASH"))
+            .transpose()?
+            .map(|addr| addr as *const u32),
+    };
+
+    let mut needed = Vec::with_capacity(info.needed.len());
+    for &name in &info.needed {
+        let name = info
+            .strtab
+            .checked_add(name)
+            .ok_or(LoadError::OutsideImage("DT_NEEDED"))
+            .and_then(|vaddr| layout.address(vaddr, 1, "DT_NEEDED"))?;
+        let name = unsafe { CStr::from_ptr(name as *const c_char) };
+        let handle = unsafe { libc::dlopen(name.as_ptr(), libc::RTLD_NOW) };
+        if handle.is_null() {
+            return Err(LoadError::NeededLibrary(
+                name.to_string_lossy().into_owned(),
+                crate::dlerror_string(),
+            ));
+        }
+        needed.push(handle);
+    }
+
+    let tls_module = match headers.iter().find(|h| h.p_type == PT_TLS) {
+        Some(tls) => Some(tls::register(
+            layout.address(to_usize(tls.p_vaddr)?, to_usize(tls.p_filesz)?, "PT_TLS")? as *const u8,
+            tls.p_filesz as usize,
+            tls.p_memsz as usize,
+            tls.p_align as usize,
+        )?),
+        None => None,
+    };
+
+    let resolver = Resolver {
+        image: &image,
+        needed: &needed,
+        tls_module,
+    };
+    unsafe {
+        apply_relr(&layout, info.relr.0, info.relr.1)?;
+        resolver.apply(info.rel, false)?;
+        resolver.apply(info.rela, true)?;
+        resolver.apply(info.jmprel, info.pltrel_is_rela)?;
+    }
+
+    protect(&loads, &headers, &layout, page)?;
+    register_unwind_tables(&headers, &layout);
+
+    let initializers = image.initializers(&info)?;
+    // The image now owns the mapping for good.
+    mapping.base = ptr::null_mut();
+    run_initializers(&initializers);
+    Ok(image)
+}
+
+/// Applies the final protections of each `PT_LOAD`, then makes `PT_GNU_RELRO` read-only.
+fn protect(
+    loads: &[&ProgramHeader],
+    headers: &[ProgramHeader],
+    layout: &Layout,
+    page: usize,
+) -> Result<(), LoadError> {
+    let bias = layout.bias();
+    // Bounded by the image size when it was laid out.
+    let range = |h: &ProgramHeader| {
+        let start = bias + h.p_vaddr as usize;
+        (
+            page_down(start, page),
+            page_up(start + h.p_memsz as usize, page),
+        )
+    };
+    for header in loads {
+        let (start, end) = range(header);
+        // Pages shared with a neighbouring segment keep the permissions of both.
+        let flags = loads
+            .iter()
+            .filter(|other| {
+                let (other_start, other_end) = range(other);
+                other_start < end && start < other_end
+            })
+            .fold(0, |flags, other| flags | other.p_flags);
+        let mut prot = 0;
+        if flags & PF_R != 0 {
+            prot |= libc::PROT_READ;
+        }
+        if flags & PF_W != 0 {
+            prot |= libc::PROT_WRITE;
+        }
+        if flags & PF_X != 0 {
+            prot |= libc::PROT_EXEC;
+        }
+        if unsafe { libc::mprotect(start as *mut c_void, end - start, prot) } != 0 {
+            return Err(LoadError::Memory(
+                "mprotect",
+                std::io::Error::last_os_error(),
+            ));
+        }
+    }
+    if let Some(relro) = headers.iter().find(|h| h.p_type == PT_GNU_RELRO) {
+        let len = to_usize(relro.p_memsz)?;
+        let start = layout.address(to_usize(relro.p_vaddr)?, len, "PT_GNU_RELRO")?;
+        let end = page_down(start + len, page);
+        let start = page_down(start, page);
+        if end > start
+            && unsafe { libc::mprotect(start as *mut c_void, end - start, libc::PROT_READ) } != 0
+        {
+            return Err(LoadError::Memory(
+                "mprotect",
+                std::io::Error::last_os_error(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Hands `.eh_frame` to the unwinder so panics and C++ exceptions can cross the image.
+fn register_unwind_tables(headers: &[ProgramHeader], layout: &Layout) {
+    const DW_EH_PE_PCREL_SDATA4: u8 = 0x1b;
+
+    let Some(eh_frame_hdr) = headers.iter().find(|h| h.p_type == PT_GNU_EH_FRAME) else {
+        return;
+    };
+    let hdr = match layout.address(eh_frame_hdr.p_vaddr as usize, 8, "PT_GNU_EH_FRAME") {
+        Ok(hdr) => hdr as *const u8,
+        Err(e) => {
+            debug!(
+                "Unwind tables of the reflective image not registered: {}",
+                e
+            );
+            return;
+        }
+    };
+    let register = unsafe { libc::dlsym(libc::RTLD_DEFAULT, c"__register_frame".as_ptr()) };
+    unsafe {
+        // version, eh_frame_ptr encoding, then the encoded eh_frame_ptr.
+        if register.is_null() || *hdr != 1 || *hdr.add(1) != DW_EH_PE_PCREL_SDATA4 {
+            debug!("Unwind tables of the reflective image not registered");
+            return;
+        }
+        let field = hdr.add(4);
+        let eh_frame = field.offset(ptr::read_unaligned(field as *const i32) as isize);
+        let register: unsafe extern "C" fn(*const u8) = std::mem::transmute(register);
+        register(eh_frame);
+    }
+}
+
+impl MappedImage {
+    unsafe fn string(&self, offset: usize) -> &CStr {
+        CStr::from_ptr(self.strtab.add(offset))
+    }
+
+    unsafe fn sym(&self, index: usize) -> &Sym {
+        &*self.symtab.add(index)
+    }
+
+    /// Address of the exported symbol `name`, found through the image's hash tables.
+    pub(crate) fn symbol(&self, name: &CStr) -> Option<*mut c_void> {
+        let index = unsafe {
+            match (self.gnu_hash, self.hash) {
+                (Some(table), _) => self.gnu_lookup(table, name.to_bytes()),
+                (None, Some(table)) => self.sysv_lookup(table, name.to_bytes()),
+                (None, None) => None,
+            }
+        }?;
+        let sym = unsafe { self.sym(index) };
+        Some((self.bias + sym.st_value) as *mut c_void)
+    }
+
+    unsafe fn defines(&self, index: usize, name: &[u8]) -> bool {
+        let sym = self.sym(index);
+        sym.st_shndx != SHN_UNDEF && self.string(sym.st_name as usize).to_bytes() == name
+    }
+
+    unsafe fn gnu_lookup(&self, table: *const u32, name: &[u8]) -> Option<usize> {
+        let nbuckets = *table as usize;
+        let symoffset = *table.add(1) as usize;
+        let bloom_size = *table.add(2) as usize;
+        if nbuckets == 0 {
+            return None;
+        }
+        let buckets = table.add(4 + bloom_size * (usize::BITS as usize / 32));
+        let chains = buckets.add(nbuckets);
+        let hash = gnu_hash(name);
+        let mut index = *buckets.add(hash as usize % nbuckets) as usize;
+        if index < symoffset {
+            return None;
+        }
+        loop {
+            let chain = *chains.add(index - symoffset);
+            if (chain | 1) == (hash | 1) && self.defines(index, name) {
+                return Some(index);
+            }
+            if chain & 1 != 0 {
+                return None;
+            }
+            index += 1;
+        }
+    }
+
+    unsafe fn sysv_lookup(&self, table: *const u32, name: &[u8]) -> Option<usize> {
+        let nbuckets = *table as usize;
+        if nbuckets == 0 {
+            return None;
+        }
+        let buckets = table.add(2);
+        let chains = buckets.add(nbuckets);
+        let mut index = *buckets.add(sysv_hash(name) as usize % nbuckets) as usize;
+        while index != 0 {
+            if self.defines(index, name) {
+                return Some(index);
+            }
+            index = *chains.add(index) as usize;
+        }
+        None
+    }
+
+    /// The init functions to run, in order: `DT_INIT`, then the relocated `DT_INIT_ARRAY`.
+    fn initializers(&self, info: &DynamicInfo) -> Result<Vec<usize>, LoadError> {
+        let mut functions = Vec::new();
+        if info.init != 0 {
+            functions.push(self.layout.address(info.init, 1, "DT_INIT")?);
+        }
+        let (array, size) = info.init_array;
+        if array != 0 {
+            let array = self.layout.address(array, size, "DT_INIT_ARRAY")? as *const usize;
+            for index in 0..size / std::mem::size_of::<usize>() {
+                let function = unsafe { ptr::read_unaligned(array.add(index)) };
+                if function != 0 && function != usize::MAX {
+                    functions.push(function);
+                }
+            }
+        }
+        Ok(functions)
+    }
+}
+
+fn run_initializers(functions: &[usize]) {
+    type Init = unsafe extern "C" fn(c_int, *const *const c_char, *const *const c_char);
+    extern "C" {
+        static environ: *const *const c_char;
+    }
+    for &function in functions {
+        unsafe {
+            let init: Init = std::mem::transmute(function);
+            init(0, ptr::null(), environ);
+        }
+    }
+}
+
+fn gnu_hash(name: &[u8]) -> u32 {
+    name.iter()
+        .fold(5381u32, |h, &c| h.wrapping_mul(33).wrapping_add(c as u32))
+}
+
+fn sysv_hash(name: &[u8]) -> u32 {
+    name.iter().fold(0u32, |h, &c| {
+        let h = (h << 4).wrapping_add(c as u32);
+        let g = h & 0xf000_0000;
+        (h ^ (g >> 24)) & !g
+    })
+}
+
+/// Applies `DT_RELR` relative relocations: an address entry relocates one word and sets the
+/// base for the bitmap entries that follow, each covering the next 63 (or 31) words.
+unsafe fn apply_relr(layout: &Layout, table: usize, size: usize) -> Result<(), LoadError> {
+    if table == 0 {
+        return Ok(());
+    }
+    const WORD: usize = std::mem::size_of::<usize>();
+    let bias = layout.bias();
+    let relocate = |vaddr: usize| -> Result<(), LoadError> {
+        let target = layout.address(vaddr, WORD, "DT_RELR target")? as *mut usize;
+        *target = (*target).wrapping_add(bias);
+        Ok(())
+    };
+    let table = layout.address(table, size, "DT_RELR")?;
+    let entries = std::slice::from_raw_parts(table as *const usize, size / WORD);
+    // Virtual address of the word after the last one relocated by an address entry.
+    let mut next = None;
+    for &entry in entries {
+        if entry & 1 == 0 {
+            relocate(entry)?;
+            next = entry.checked_add(WORD);
+        } else {
+            let base = next.ok_or(LoadError::OutsideImage("DT_RELR bitmap"))?;
+            let mut bits = entry >> 1;
+            let mut vaddr = base;
+            while bits != 0 {
+                if bits & 1 != 0 {
+                    relocate(vaddr)?;
+                }
+                bits >>= 1;
+                vaddr = vaddr.wrapping_add(WORD);
+            }
+            next = base.checked_add((usize::BITS as usize - 1) * WORD);
+        }
+    }
+    Ok(())
+}
+
+/// Resolves symbols and applies REL/RELA tables.
+struct Resolver<'a> {
+    image: &'a MappedImage,
+    needed: &'a [*mut c_void],
+    tls_module: Option<usize>,
+}
+
+impl Resolver<'_> {
+    /// Address of the symbol at `index`: the image's own definition, otherwise the first
+    /// `DT_NEEDED` library or global symbol of that name. Unresolved weak symbols are 0.
+    unsafe fn lookup(&self, index: usize) -> Result<usize, LoadError> {
+        let sym = self.image.sym(index);
+        if sym.st_shndx != SHN_UNDEF {
+            return Ok(self.image.bias + sym.st_value);
+        }
+        let name = self.image.string(sym.st_name as usize);
+        if let Some(address) = tls::interpose(name) {
+            return Ok(address);
+        }
+        let found = self
+            .needed
+            .iter()
+            .copied()
+            .chain(std::iter::once(libc::RTLD_DEFAULT))
+            .map(|handle| libc::dlsym(handle, name.as_ptr()))
+            .find(|address| !address.is_null());
+        match found {
+            Some(address) => Ok(address as usize),
+            None if sym.st_info >> 4 == STB_WEAK => Ok(0),
+            None => Err(LoadError::UnresolvedSymbol(
+                name.to_string_lossy().into_owned(),
+            )),
+        }
+    }
+
+    /// Applies a REL (`rela == false`) or RELA table of `size` bytes at `table`.
+    unsafe fn apply(&self, (table, size): (usize, usize), rela: bool) -> Result<(), LoadError> {
+        if table == 0 {
+            return Ok(());
+        }
+        const WORD: usize = std::mem::size_of::<usize>();
+        let words = if rela { 3 } else { 2 };
+        let entry_size = words * WORD;
+        let bias = self.image.bias;
+        let layout = &self.image.layout;
+        let table = layout.address(table, size, "relocation table")? as *const usize;
+        for index in 0..size / entry_size {
+            let entry = table.add(index * words);
+            let offset = *entry;
+            let info = *entry.add(1);
+            let target = layout.address(offset, WORD, "relocation target")? as *mut usize;
+            // REL entries keep their addend in the relocated word.
+            let addend = if rela { *entry.add(2) } else { *target };
+            let kind = r_type(info);
+            let value = match classify(kind).ok_or(LoadError::UnsupportedRelocation(kind))? {
+                Reloc::None => continue,
+                Reloc::Absolute => self.lookup(r_sym(info))?.wrapping_add(addend),
+                Reloc::Symbol => {
+                    let symbol = self.lookup(r_sym(info))?;
+                    if rela {
+                        symbol.wrapping_add(addend)
+                    } else {
+                        symbol
+                    }
+                }
+                Reloc::Relative => bias.wrapping_add(addend),
+                Reloc::IRelative => {
+                    let resolver: unsafe extern "C" fn() -> usize =
+                        std::mem::transmute(bias.wrapping_add(addend));
+                    resolver()
+                }
+                Reloc::TlsModule => self.tls_module.ok_or(LoadError::UnsupportedTls)?,
+                Reloc::TlsOffset => {
+                    let sym = self.image.sym(r_sym(info));
+                    sym.st_value.wrapping_add(addend)
+                }
+            };
+            *target = value;
+        }
+        Ok(())
+    }
+}
+
+/// Thread-local storage for reflective images on x86_64.
+///
+/// Code compiled for the general and local dynamic models calls `__tls_get_addr` with a
+/// `(module, offset)` pair. Imports of `__tls_get_addr` are bound to [get_addr], which serves
+/// tagged module ids from a per-thread block (kept in a pthread key and filled from the TLS
+/// image on first use) and forwards every other module to the real implementation.
+#[cfg(target_arch = "x86_64")]
+mod tls {
+    use std::ffi::{c_void, CStr};
+    use std::sync::OnceLock;
+
+    use super::LoadError;
+
+    /// Set on module ids owned by reflective images; real ids are small integers.
+    const MODULE_TAG: usize = 1 << 63;
+
+    #[repr(C)]
+    struct TlsIndex {
+        module: usize,
+        offset: usize,
+    }
+
+    struct TlsModule {
+        key: libc::pthread_key_t,
+        image: *const u8,
+        filesz: usize,
+        memsz: usize,
+        align: usize,
+    }
+
+    type GetAddr = unsafe extern "C" fn(*const TlsIndex) -> *mut c_void;
+
+    static REAL_GET_ADDR: OnceLock<usize> = OnceLock::new();
+
+    pub(super) fn interpose(name: &CStr) -> Option<usize> {
+        (name == c"__tls_get_addr").then_some(get_addr as GetAddr as usize)
+    }
+
+    /// Registers the TLS segment of an image and returns its module id.
+    pub(super) fn register(
+        image: *const u8,
+        filesz: usize,
+        memsz: usize,
+        align: usize,
+    ) -> Result<usize, LoadError> {
+        REAL_GET_ADDR.get_or_init(|| unsafe {
+            libc::dlsym(libc::RTLD_DEFAULT, c"__tls_get_addr".as_ptr()) as usize
+        });
+        let mut key = 0;
+        if unsafe { libc::pthread_key_create(&mut key, Some(libc::free)) } != 0 {
+            return Err(LoadError::Memory(
+                "pthread_key_create",
+                std::io::Error::last_os_error(),
+            ));
+        }
+        let module = Box::leak(Box::new(TlsModule {
+            key,
+            image,
+            filesz,
+            memsz,
+            align: align.max(std::mem::size_of::<usize>()),
+        }));
+        Ok(module as *mut TlsModule as usize | MODULE_TAG)
+    }
+
+    unsafe extern "C" fn get_addr(index: *const TlsIndex) -> *mut c_void {
+        let index = &*index;
+        if index.module & MODULE_TAG == 0 {
+            let real: GetAddr = std::mem::transmute(*REAL_GET_ADDR.get().unwrap());
+            return real(index);
+        }
+        let module = &*((index.module & !MODULE_TAG) as *const TlsModule);
+        let mut block = libc::pthread_getspecific(module.key) as *mut u8;
+        if block.is_null() {
+            let mut memory = std::ptr::null_mut();
+            if libc::posix_memalign(&mut memory, module.align, module.memsz.max(1)) != 0 {
+                std::process::abort();
+            }
+            block = memory as *mut u8;
+            std::ptr::copy_nonoverlapping(module.image, block, module.filesz);
+            std::ptr::write_bytes(block.add(module.filesz), 0, module.memsz - module.filesz);
+            libc::pthread_setspecific(module.key, memory);
+        }
+        block.add(index.offset) as *mut c_void
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+mod tls {
+    use std::ffi::CStr;
+
+    use super::LoadError;
+
+    pub(super) fn interpose(_name: &CStr) -> Option<usize> {
+        None
+    }
+
+    pub(super) fn register(
+        _image: *const u8,
+        _filesz: usize,
+        _memsz: usize,
+        _align: usize,
+    ) -> Result<usize, LoadError> {
+        Err(LoadError::UnsupportedTls)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    /// Builds `tests/sample-payload` once and returns the library bytes.
+    pub(crate) fn sample_payload() -> &'static [u8] {
+        static SAMPLE: std::sync::OnceLock<Vec<u8>> = std::sync::OnceLock::new();
+        SAMPLE.get_or_init(|| {
+            let root = env!("CARGO_MANIFEST_DIR");
+            let target_dir = format!("{}/target/sample-payload", root);
+            let status = std::process::Command::new(env!("CARGO"))
+                .args(["build", "--release", "--quiet", "--manifest-path"])
+                .arg(format!("{}/tests/sample-payload/Cargo.toml", root))
+                .arg("--target-dir")
+                .arg(&target_dir)
+                .status()
+                .unwrap();
+            assert!(status.success(), "failed to build the sample payload");
+            std::fs::read(format!("{}/release/libsample_payload.so", target_dir)).unwrap()
+        })
+    }
+
+    #[test]
+    fn hashes_symbol_names() {
+        // Reference values from the GNU and System V ABI documents.
+        assert_eq!(gnu_hash(b""), 0x0000_1505);
+        assert_eq!(gnu_hash(b"printf"), 0x156b_2bb8);
+        assert_eq!(sysv_hash(b""), 0);
+        assert_eq!(sysv_hash(b"printf"), 0x0779_05a6);
+    }
+
+    #[test]
+    fn applies_relr_bitmaps() {
+        const WORD: usize = std::mem::size_of::<usize>();
+        // Words 0, 1, 3 and 5 need relocating: one address entry for word 0, then a bitmap
+        // whose bits cover words 1, 2, 3, ...
+        let bitmap = (0b10101 << 1) | 1;
+        // The table itself sits in the last two words.
+        let mut memory = [10usize, 20, 30, 40, 50, 60, 0, bitmap];
+        let base = memory.as_mut_ptr() as usize;
+        // With a zero image address the bias is the address of `memory` itself.
+        let layout = Layout {
+            base,
+            min_vaddr: 0,
+            size: memory.len() * WORD,
+        };
+        unsafe { apply_relr(&layout, 6 * WORD, 2 * WORD).unwrap() };
+        assert_eq!(
+            memory,
+            [
+                10 + base,
+                20 + base,
+                30,
+                40 + base,
+                50,
+                60 + base,
+                0,
+                bitmap
+            ]
+        );
+
+        // Entries outside the image are rejected, not written.
+        let mut memory = [0usize, 8 * WORD];
+        let layout = Layout {
+            base: memory.as_mut_ptr() as usize,
+            min_vaddr: 0,
+            size: memory.len() * WORD,
+        };
+        assert!(matches!(
+            unsafe { apply_relr(&layout, WORD, WORD) },
+            Err(LoadError::OutsideImage(_))
+        ));
+        assert!(matches!(
+            unsafe { apply_relr(&layout, WORD, 2 * WORD) },
+            Err(LoadError::OutsideImage(_))
+        ));
+        assert_eq!(memory[1], 8 * WORD);
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    mod sample {
+        use super::super::*;
+        use super::sample_payload;
+        use std::sync::OnceLock;
+
+        fn sample_image() -> &'static MappedImage {
+            struct Shared(MappedImage);
+            unsafe impl Send for Shared {}
+            unsafe impl Sync for Shared {}
+            static IMAGE: OnceLock<Shared> = OnceLock::new();
+            &IMAGE
+                .get_or_init(|| Shared(load(sample_payload()).unwrap()))
+                .0
+        }
+
+        unsafe fn function<F: Copy>(name: &CStr) -> F {
+            let address = sample_image().symbol(name).unwrap();
+            std::mem::transmute_copy(&address)
+        }
+
+        #[test]
+        fn loads_sample_payload_without_a_file() {
+            let image = sample_image();
+            let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+            assert!(!maps.contains("sample_payload"));
+            assert!(image.symbol(c"sample_missing").is_none());
+
+            unsafe {
+                let init_calls: extern "C" fn() -> u32 = function(c"sample_init_calls");
+                assert_eq!(init_calls(), 1);
+                let add: extern "C" fn(u32, u32) -> u32 = function(c"sample_add");
+                assert_eq!(add(40, 2), 42);
+                let greeting: extern "C" fn(usize) -> *const c_char = function(c"sample_greeting");
+                assert_eq!(CStr::from_ptr(greeting(1)), c"from the sample payload");
+                let strlen: extern "C" fn(*const c_char) -> usize = function(c"sample_strlen");
+                assert_eq!(strlen(greeting(0)), 5);
+                let format_len: extern "C" fn(u64) -> usize = function(c"sample_format_len");
+                assert_eq!(format_len(1234), 13);
+            }
+        }
+
+        #[test]
+        fn gives_each_thread_its_own_tls() {
+            unsafe {
+                let bump: extern "C" fn() -> u32 = function(c"sample_tls_bump");
+                let first = bump();
+                assert_eq!(bump(), first + 1);
+                let other = std::thread::spawn(move || (bump(), bump())).join().unwrap();
+                assert_eq!(other, (1, 2));
+                assert_eq!(bump(), first + 2);
+            }
+        }
+
+        #[test]
+        fn unwinds_through_the_image() {
+            unsafe {
+                let catch_panic: extern "C" fn() -> bool = function(c"sample_catch_panic");
+                assert!(catch_panic());
+            }
+        }
+
+        #[test]
+        fn rejects_truncated_images() {
+            let data = sample_payload();
+            assert!(matches!(
+                load(&data[..32]),
+                Err(LoadError::Elf(ElfError::Truncated(_)))
+            ));
+        }
+
+        /// Offset of the first program header of type `p_type` in the sample.
+        fn header_offset(data: &[u8], p_type: u32) -> usize {
+            let phoff = u64::from_le_bytes(data[32..40].try_into().unwrap()) as usize;
+            let phnum = u16::from_le_bytes(data[56..58].try_into().unwrap()) as usize;
+            (0..phnum)
+                .map(|index| phoff + index * 56)
+                .find(|&offset| data[offset..offset + 4] == p_type.to_le_bytes())
+                .unwrap()
+        }
+
+        #[test]
+        fn rejects_layouts_outside_the_image() {
+            let patched = |p_type: u32, field: usize, value: u64| {
+                let mut data = sample_payload().to_vec();
+                let offset = header_offset(&data, p_type) + field;
+                data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+                load(&data).err()
+            };
+            // p_memsz overflowing the address space.
+            assert!(matches!(
+                patched(PT_LOAD, 40, u64::MAX - 0x10),
+                Some(LoadError::OutsideImage(_))
+            ));
+            // p_offset past the end of the file.
+            assert!(matches!(
+                patched(PT_LOAD, 8, 1 << 40),
+                Some(LoadError::Elf(ElfError::SegmentOutOfBounds(_)))
+            ));
+            // PT_DYNAMIC outside every PT_LOAD.
+            assert!(matches!(
+                patched(PT_DYNAMIC, 16, 1 << 40),
+                Some(LoadError::OutsideImage("PT_DYNAMIC"))
+            ));
+            assert!(matches!(
+                patched(PT_DYNAMIC, 40, u64::MAX),
+                Some(LoadError::OutsideImage("PT_DYNAMIC"))
+            ));
+        }
+    }
+}
@@ -0,0 +1,235 @@
+//! Ed25519 payload signatures.
+//!
+//! When a trust store exists, every payload must come with a detached signature in
+//! `<payload>.sig` made by one of the trusted keys. Keys and signatures are stored as hex text,
+//! one key per line in the trust store, so they can be pushed and inspected with a shell.
+//!
+//! Signatures cover the payload file exactly as stored, before any decoding.
+//!
+//! The signing side is only built for tests and the `host-tools` feature, which provides the
+//! `zygisk-loader-sign` tool.
+
+use std::fmt;
+#[cfg(any(test, feature = "host-tools"))]
+use std::fs;
+use std::io;
+
+use ed25519_dalek::{Signature, VerifyingKey};
+
+#[derive(Debug)]
+pub(crate) enum SignatureError {
+    /// The trust store exists but could not be read.
+    Unreadable(io::Error),
+    /// A trust store line that is not a valid public key, by 1-based line number.
+    InvalidKey(usize),
+    /// The `.sig` file could not be read.
+    Missing(io::Error),
+    /// The `.sig` file is not a hex-encoded 64-byte signature.
+    Malformed,
+    /// The signature does not match any trusted key.
+    Untrusted,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::Unreadable(e) => write!(f, "trust store unreadable: {}", e),
+            SignatureError::InvalidKey(line) => write!(f, "invalid public key on line {}", line),
+            SignatureError::Missing(e) => write!(f, "no signature: {}", e),
+            SignatureError::Malformed => write!(f, "malformed signature"),
+            SignatureError::Untrusted => write!(f, "signature does not match any trusted key"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// Path of the detached signature of `payload_path`.
+pub(crate) fn signature_path(payload_path: &str) -> String {
+    format!("{}.sig", payload_path)
+}
+
+/// The set of public keys payloads may be signed with.
+pub(crate) struct TrustStore {
+    keys: Vec<VerifyingKey>,
+}
+
+impl TrustStore {
+    /// Loads the trust store. Returns `Ok(None)` when the file does not exist, in which case
+    /// signatures are not enforced.
+    #[cfg(feature = "host-tools")]
+    pub(crate) fn load(path: &str) -> Result<Option<TrustStore>, SignatureError> {
+        TrustStore::from_file(fs::read(path))
+    }
+
+    /// Like [TrustStore::load], from the outcome of reading the file by other means.
+    pub(crate) fn from_file(
+        contents: io::Result<Vec<u8>>,
+    ) -> Result<Option<TrustStore>, SignatureError> {
+        match contents {
+            Ok(bytes) => TrustStore::parse(&String::from_utf8_lossy(&bytes)).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(SignatureError::Unreadable(e)),
+        }
+    }
+
+    /// Parses one hex-encoded public key per line. Empty lines and `#` comments are ignored.
+    pub(crate) fn parse(text: &str) -> Result<TrustStore, SignatureError> {
+        let mut keys = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let key = decode_hex::<32>(line)
+                .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+                .ok_or(SignatureError::InvalidKey(index + 1))?;
+            keys.push(key);
+        }
+        Ok(TrustStore { keys })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Verifies the hex-encoded `signature` of `data` against every trusted key.
+    pub(crate) fn verify(&self, data: &[u8], signature: &str) -> Result<(), SignatureError> {
+        let signature = decode_hex::<64>(signature.trim())
+            .map(|bytes| Signature::from_bytes(&bytes))
+            .ok_or(SignatureError::Malformed)?;
+        self.keys
+            .iter()
+            .any(|key| key.verify_strict(data, &signature).is_ok())
+            .then_some(())
+            .ok_or(SignatureError::Untrusted)
+    }
+
+    /// Verifies `data` against the signature stored next to `payload_path`.
+    #[cfg(feature = "host-tools")]
+    pub(crate) fn verify_file(
+        &self,
+        payload_path: &str,
+        data: &[u8],
+    ) -> Result<(), SignatureError> {
+        self.verify_signature_file(data, fs::read(signature_path(payload_path)))
+    }
+
+    /// Like [TrustStore::verify_file], from the outcome of reading the `.sig` file by other
+    /// means.
+    pub(crate) fn verify_signature_file(
+        &self,
+        data: &[u8],
+        signature: io::Result<Vec<u8>>,
+    ) -> Result<(), SignatureError> {
+        let signature = signature.map_err(SignatureError::Missing)?;
+        self.verify(data, &String::from_utf8_lossy(&signature))
+    }
+}
+
+#[cfg(any(test, feature = "host-tools"))]
+pub(crate) fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn decode_hex<const N: usize>(text: &str) -> Option<[u8; N]> {
+    if text.len() != N * 2 || !text.is_ascii() {
+        return None;
+    }
+    let mut out = [0u8; N];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(out)
+}
+
+/// A secret signing key, derived from a 32-byte seed.
+#[cfg(any(test, feature = "host-tools"))]
+pub(crate) struct SecretKey(ed25519_dalek::SigningKey);
+
+#[cfg(any(test, feature = "host-tools"))]
+impl SecretKey {
+    pub(crate) fn from_seed(seed: &[u8; 32]) -> SecretKey {
+        SecretKey(ed25519_dalek::SigningKey::from_bytes(seed))
+    }
+
+    /// Creates a key from `/dev/urandom`.
+    pub(crate) fn generate() -> io::Result<SecretKey> {
+        use std::io::Read;
+        let mut seed = [0u8; 32];
+        fs::File::open("/dev/urandom")?.read_exact(&mut seed)?;
+        Ok(SecretKey::from_seed(&seed))
+    }
+
+    pub(crate) fn from_hex(text: &str) -> Option<SecretKey> {
+        decode_hex::<32>(text.trim()).map(|seed| SecretKey::from_seed(&seed))
+    }
+
+    pub(crate) fn to_hex(&self) -> String {
+        encode_hex(self.0.as_bytes())
+    }
+
+    /// The public key, as a trust store line.
+    pub(crate) fn public_hex(&self) -> String {
+        encode_hex(self.0.verifying_key().as_bytes())
+    }
+
+    /// Signs `data`, returning the content of the `.sig` file.
+    pub(crate) fn sign(&self, data: &[u8]) -> String {
+        use ed25519_dalek::Signer;
+        encode_hex(&self.0.sign(data).to_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sign_and_verify_round_trip() {
+        let trusted = SecretKey::from_seed(&[7; 32]);
+        let other = SecretKey::from_seed(&[9; 32]);
+        let store = TrustStore::parse(&format!(
+            "# release key\n{}\n\n{}\n",
+            SecretKey::from_seed(&[1; 32]).public_hex(),
+            trusted.public_hex()
+        ))
+        .unwrap();
+        assert_eq!(store.len(), 2);
+
+        let payload = b"\x7fELF payload bytes";
+        assert!(store.verify(payload, &trusted.sign(payload)).is_ok());
+        assert!(matches!(
+            store.verify(b"\x7fELF tampered bytes", &trusted.sign(payload)),
+            Err(SignatureError::Untrusted)
+        ));
+        assert!(matches!(
+            store.verify(payload, &other.sign(payload)),
+            Err(SignatureError::Untrusted)
+        ));
+        assert!(matches!(
+            store.verify(payload, "not hex"),
+            Err(SignatureError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn secret_key_hex_round_trip() {
+        let key = SecretKey::from_seed(&[3; 32]);
+        let restored = SecretKey::from_hex(&key.to_hex()).unwrap();
+        assert_eq!(restored.public_hex(), key.public_hex());
+
+        let generated = SecretKey::generate().unwrap();
+        assert_ne!(generated.to_hex(), key.to_hex());
+        assert_eq!(
+            decode_hex::<32>(&generated.public_hex()).map(|b| b.len()),
+            Some(32)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_trust_store_lines() {
+        let err = TrustStore::parse("# keys\n\nabcd\n").err().unwrap();
+        assert!(matches!(err, SignatureError::InvalidKey(3)));
+    }
+}
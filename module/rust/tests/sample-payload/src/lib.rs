@@ -0,0 +1,126 @@
+//! Exercises what a real payload needs from its loader: relocated data, imports from libc,
+//! init functions, thread-locals, the allocator and unwinding, plus the loader entry points.
+
+use std::cell::Cell;
+use std::ffi::{c_char, c_void, CStr};
+use std::sync::atomic::{AtomicI32, AtomicPtr, AtomicU32, Ordering};
+
+static INIT_CALLS: AtomicU32 = AtomicU32::new(0);
+
+extern "C" fn on_load() {
+    INIT_CALLS.fetch_add(1, Ordering::SeqCst);
+}
+
+#[used]
+#[link_section = ".init_array"]
+static ON_LOAD: extern "C" fn() = on_load;
+
+// Pointers in static data need relative relocations.
+static GREETINGS: [&CStr; 2] = [c"hello", c"from the sample payload"];
+
+thread_local! {
+    static COUNTER: Cell<u32> = const { Cell::new(0) };
+}
+
+extern "C" {
+    fn strlen(s: *const c_char) -> usize;
+}
+
+#[no_mangle]
+pub extern "C" fn sample_init_calls() -> u32 {
+    INIT_CALLS.load(Ordering::SeqCst)
+}
+
+#[no_mangle]
+pub extern "C" fn sample_add(a: u32, b: u32) -> u32 {
+    a.wrapping_add(b)
+}
+
+#[no_mangle]
+pub extern "C" fn sample_greeting(index: usize) -> *const c_char {
+    GREETINGS[index % GREETINGS.len()].as_ptr()
+}
+
+#[no_mangle]
+pub extern "C" fn sample_strlen(s: *const c_char) -> usize {
+    unsafe { strlen(s) }
+}
+
+#[no_mangle]
+pub extern "C" fn sample_tls_bump() -> u32 {
+    COUNTER.with(|counter| {
+        counter.set(counter.get() + 1);
+        counter.get()
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn sample_format_len(n: u64) -> usize {
+    format!("payload #{}", n).len()
+}
+
+#[no_mangle]
+pub extern "C" fn sample_catch_panic() -> bool {
+    std::panic::set_hook(Box::new(|_| {}));
+    std::panic::catch_unwind(|| panic!("caught")).is_err()
+}
+
+include!("../../../include/zygisk_loader.rs");
+
+static ENTRY_SUMMARY: std::sync::Mutex<Option<std::ffi::CString>> = std::sync::Mutex::new(None);
+
+/// Records what the loader passed in; returns the `exit_code` argument, if any.
+#[no_mangle]
+pub unsafe extern "C" fn zygisk_loader_main(context: *const ZygiskLoaderContext) -> i32 {
+    let context = &*context;
+    let text = |s: *const c_char| CStr::from_ptr(s).to_string_lossy().into_owned();
+    let mut summary = format!(
+        "v{} {} {} {} {} {}",
+        context.version,
+        text(context.process_name),
+        text(context.package_name),
+        text(context.app_data_dir),
+        text(context.instruction_set),
+        context.uid
+    );
+    let mut exit_code = 0;
+    for arg in std::slice::from_raw_parts(context.args, context.arg_count) {
+        let (key, value) = (text(arg.key), text(arg.value));
+        if key == "exit_code" {
+            exit_code = value.parse().unwrap_or(-1);
+        }
+        summary.push_str(&format!(" {}={}", key, value));
+    }
+    *ENTRY_SUMMARY.lock().unwrap() = std::ffi::CString::new(summary).ok();
+    exit_code
+}
+
+#[no_mangle]
+pub extern "C" fn sample_entry_summary() -> *const c_char {
+    ENTRY_SUMMARY
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map_or(std::ptr::null(), |summary| summary.as_ptr())
+}
+
+static JNI_VERSION: AtomicI32 = AtomicI32::new(0x0001_0006);
+static JNI_VM: AtomicPtr<c_void> = AtomicPtr::new(std::ptr::null_mut());
+
+/// Records the `JavaVM *` without touching it; tests pass a fake one.
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "C" fn JNI_OnLoad(vm: *mut c_void, _reserved: *mut c_void) -> i32 {
+    JNI_VM.store(vm, Ordering::SeqCst);
+    JNI_VERSION.load(Ordering::SeqCst)
+}
+
+#[no_mangle]
+pub extern "C" fn sample_set_jni_version(version: i32) {
+    JNI_VERSION.store(version, Ordering::SeqCst);
+}
+
+#[no_mangle]
+pub extern "C" fn sample_jni_vm() -> *mut c_void {
+    JNI_VM.load(Ordering::SeqCst)
+}